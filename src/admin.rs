@@ -0,0 +1,113 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    auth::Admin,
+    connections::{self, Connection, LiveConnection},
+    error::AppError,
+    state::AppState,
+};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/admin/broadcast", get(get_broadcast))
+        .route("/admin/broadcast/resize", post(resize_broadcast))
+        .route("/admin/connections", get(get_connections))
+        .route("/admin/connections/live", get(get_live_connections))
+        .route("/admin/connections/:id/kick", post(kick_connection))
+}
+
+// largest broadcast buffer an admin may ask for, every socket can hold this many frames
+const MAX_BROADCAST_CAPACITY: usize = 65_536;
+
+#[derive(Serialize)]
+pub struct BroadcastInfo {
+    // sockets currently subscribed
+    pub receiver_count: usize,
+    // frames buffered for a slow socket before it lags and is disconnected
+    pub capacity: usize,
+}
+
+#[derive(Deserialize)]
+pub struct ResizeBroadcast {
+    pub capacity: usize,
+}
+
+fn broadcast_info(state: &AppState) -> BroadcastInfo {
+    let broadcast = state.broadcast.read().unwrap();
+    BroadcastInfo {
+        receiver_count: broadcast.tx.receiver_count(),
+        capacity: broadcast.capacity,
+    }
+}
+
+async fn get_broadcast(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+) -> (StatusCode, Json<BroadcastInfo>) {
+    (StatusCode::OK, Json(broadcast_info(&state)))
+}
+
+// Swaps in a broadcast channel of the new capacity, connected sockets are closed and have to reconnect
+async fn resize_broadcast(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ResizeBroadcast>,
+) -> Result<(StatusCode, Json<BroadcastInfo>), AppError> {
+    if payload.capacity == 0 || payload.capacity > MAX_BROADCAST_CAPACITY {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("capacity must be between 1 and {MAX_BROADCAST_CAPACITY}"),
+        ));
+    }
+
+    state.resize_broadcast(payload.capacity);
+
+    Ok((StatusCode::OK, Json(broadcast_info(&state))))
+}
+
+#[derive(Deserialize)]
+pub struct ConnectionsQuery {
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+// The newest WebSocket sessions recorded with LOG_CONNECTIONS, optionally only one user's
+async fn get_connections(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ConnectionsQuery>,
+) -> Result<(StatusCode, Json<Vec<Connection>>), AppError> {
+    let limit = state.config.pagination.limit(query.limit)?;
+    let connections = connections::recent(&state, query.user_id, limit).await;
+
+    Ok((StatusCode::OK, Json(connections)))
+}
+
+// The sockets open on this instance, whether or not LOG_CONNECTIONS is set
+async fn get_live_connections(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+) -> (StatusCode, Json<Vec<LiveConnection>>) {
+    (StatusCode::OK, Json(state.live_connections.list()))
+}
+
+// Closes the socket with a close frame, `404` unless it's open on this instance. The client may reconnect
+async fn kick_connection(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if !state.live_connections.kick(&id) {
+        return Err(AppError::new(StatusCode::NOT_FOUND, "connection not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
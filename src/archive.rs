@@ -0,0 +1,38 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{msg, state::AppState};
+
+// how often idle channels are looked for, archiving a little late doesn't matter
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Archives channels idle for ARCHIVE_IDLE_DAYS for as long as the server runs. A channel is idle since its
+// newest message, or since it was created or last unarchived if that's later, so unarchiving a quiet channel
+// gives it a full period before it's archived again
+pub async fn run(state: Arc<AppState>, idle: Duration) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let cutoff = msg::now_millis().saturating_sub(idle.as_millis() as u64);
+        let archived = state
+            .call_db("archive_idle_channels", move |conn| {
+                conn.prepare(
+                    "UPDATE channels SET archived = 1 WHERE NOT archived AND MAX(
+                         created_at,
+                         COALESCE(unarchived_at, 0),
+                         COALESCE((SELECT MAX(time) FROM messages WHERE channel = channels.name), 0)
+                     ) < ? RETURNING name;",
+                )
+                .unwrap()
+                .query_map([cutoff], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<Vec<String>, rusqlite::Error>>()
+                .unwrap()
+            })
+            .await;
+
+        if !archived.is_empty() {
+            tracing::info!(channels = ?archived, "archived idle channels");
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use std::sync::Arc;
+
+use crate::{error::AppError, state::AppState};
+
+// Extractor that only succeeds when the request carries `Authorization: Bearer <ADMIN_TOKEN>`
+pub struct Admin;
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Admin {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(admin_token) = &state.config.admin_token else {
+            return Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "admin access disabled",
+            ));
+        };
+
+        let TypedHeader(Authorization(bearer)) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+                .await
+                .map_err(|_| AppError::new(StatusCode::UNAUTHORIZED, "missing admin token"))?;
+
+        if bearer.token() != admin_token {
+            return Err(AppError::new(StatusCode::FORBIDDEN, "invalid admin token"));
+        }
+
+        Ok(Admin)
+    }
+}
@@ -0,0 +1,53 @@
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::AppError;
+
+// Extractor for a body sent as JSON or, for simple clients and webhooks, as a urlencoded form,
+// anything else is turned away with `415 Unsupported Media Type`
+pub struct JsonOrForm<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonOrForm<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .is_some_and(|mime| {
+                mime.trim()
+                    .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            });
+
+        if is_form {
+            let Form(payload) = Form::<T>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self(payload));
+        }
+
+        // JSON rejections pass through as before, only the missing content type becomes a 415
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(payload)) => Ok(Self(payload)),
+            Err(JsonRejection::MissingJsonContentType(_)) => Err(AppError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "expected an application/json or application/x-www-form-urlencoded body",
+            )
+            .into_response()),
+            Err(rejection) => Err(rejection.into_response()),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::post,
+    Json, Router,
+};
+use rusqlite::{ErrorCode, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    auth::Admin,
+    body::JsonOrForm,
+    crypto,
+    error::{AppError, ValidationError},
+    messages, msg,
+    state::AppState,
+    users,
+};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/bots", post(create_bot))
+        .route("/integrations/:token/messages", post(post_message))
+}
+
+#[derive(Deserialize)]
+pub struct CreateBot {
+    pub username: String,
+}
+
+// A bot user with the token integrations post as it with, the token is only ever shown here
+#[derive(Serialize)]
+pub struct Bot {
+    pub user_id: String,
+    pub username: String,
+    pub token: String,
+}
+
+// What an integration sends, the bot's user is the author and the time is when it arrived
+#[derive(Deserialize)]
+pub struct IntegrationMessage {
+    pub text: String,
+    // blank goes to the default channel, like any other message
+    #[serde(default)]
+    pub channel: String,
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub format: msg::MessageFormat,
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+// Registers a bot user and issues the token external systems post as it with
+async fn create_bot(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    JsonOrForm(payload): JsonOrForm<CreateBot>,
+) -> Result<(StatusCode, Json<Bot>), AppError> {
+    let mut errors = ValidationError::default();
+    users::check_username(&state.config, &payload.username, &mut errors);
+    errors.check()?;
+
+    let bot = Bot {
+        user_id: uuidv7::create(),
+        username: payload.username,
+        token: crypto::random_token(),
+    };
+    let (user_id, username, token) = (bot.user_id.clone(), bot.username.clone(), bot.token.clone());
+    let now = msg::now_millis();
+
+    state
+        .call_db("create_bot", move |conn| {
+            let failed = |err: rusqlite::Error| {
+                tracing::error!("failed to create bot: {err}");
+                AppError::new(StatusCode::INTERNAL_SERVER_ERROR, "failed to create bot")
+            };
+            let tx = conn.transaction().map_err(failed)?;
+            let inserted = tx.execute(
+                "INSERT INTO users (id, username) VALUES (?, ?)",
+                [&user_id, &username],
+            );
+            match inserted {
+                Ok(_) => {}
+                Err(err) if err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) => {
+                    return Err(AppError::new(StatusCode::CONFLICT, "username taken"));
+                }
+                Err(err) => return Err(failed(err)),
+            }
+            tx.execute(
+                "INSERT INTO bots (token, user_id, created_at) VALUES (?, ?, ?)",
+                rusqlite::params![token, user_id, now],
+            )
+            .map_err(failed)?;
+            tx.commit().map_err(failed)
+        })
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(bot)))
+}
+
+// Posts a message as the token's bot, stored and broadcast like any other. Each token has its own bot user,
+// so BOT_MESSAGES_PER_MINUTE limits each token separately
+async fn post_message(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+    JsonOrForm(payload): JsonOrForm<IntegrationMessage>,
+) -> Result<(StatusCode, Json<msg::Message>), AppError> {
+    let bot: Option<(String, String)> = state
+        .call_db("get_bot", move |conn| {
+            conn.query_row(
+                "SELECT u.id, u.username FROM bots b JOIN users u ON u.id = b.user_id WHERE b.token = ?",
+                [token],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .unwrap()
+        })
+        .await;
+    let Some((user_id, username)) = bot else {
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid integration token",
+        ));
+    };
+
+    let payload = msg::CreateMessage {
+        id: None,
+        time: msg::now_millis(),
+        user_id,
+        username,
+        text: payload.text,
+        channel: payload.channel,
+        reply_to: payload.reply_to,
+        quote_of: None,
+        ttl_secs: payload.ttl_secs,
+        format: payload.format,
+        metadata: payload.metadata,
+        idempotency_key: None,
+    };
+    let prepared = messages::prepare_message(&state, payload, false)
+        .await?
+        .with_max_messages_per_minute(state.config.bot_messages_per_minute);
+    let (msg, _) = messages::store_prepared(&state, prepared, false).await?;
+
+    Ok((StatusCode::CREATED, Json(msg)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn db_error_creating_a_bot_is_a_server_error() {
+        let state = crate::state::test_state(|_| {});
+        state
+            .call_db("drop_bots", |conn| {
+                conn.execute_batch("DROP TABLE bots").unwrap()
+            })
+            .await;
+
+        let Err(err) = create_bot(
+            Admin,
+            State(state.clone()),
+            JsonOrForm(CreateBot {
+                username: String::from("deploys"),
+            }),
+        )
+        .await
+        else {
+            panic!("a bot was created without a bots table");
+        };
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+        // the user row was rolled back with the rest, and the db is still there
+        let users: u64 = state
+            .call_db("count_users", |conn| {
+                conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))
+                    .unwrap()
+            })
+            .await;
+        assert_eq!(users, 0);
+    }
+}
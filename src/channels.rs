@@ -0,0 +1,536 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post, put},
+    Error, Json, Router,
+};
+use rusqlite::{types::Value, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    auth::Admin,
+    body::JsonOrForm,
+    error::{AppError, ValidationError},
+    messages::{message_filters, where_clause, StoredMessage, MESSAGE_COLUMNS},
+    msg,
+    state::AppState,
+    ws,
+};
+
+// topics are shown in channel headers, so they're kept short
+const MAX_TOPIC_LEN: usize = 250;
+// longer than a day is better done by archiving the channel
+const MAX_SLOW_MODE_SECS: u64 = 24 * 60 * 60;
+
+// Columns selected by every channel query, in the order `Channel::from_row` reads them
+pub const CHANNEL_COLUMNS: &str =
+    "name, max_messages, slow_mode_secs, created_at, topic, created_by, archived";
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/channels", get(get_channels).post(create_channel))
+        .route("/channels/previews", get(get_previews))
+        .route("/channels/:name", put(update_channel))
+        .route("/channels/:name/topic", put(update_topic))
+        .route("/channels/:name/unarchive", post(unarchive_channel))
+        .route("/channels/:name/participants", get(get_participants))
+}
+
+// Per-channel settings, channels without a row use the defaults
+#[derive(Serialize, Clone, Debug)]
+pub struct Channel {
+    pub name: String,
+    // only the newest `max_messages` are kept, unbounded when `None`
+    pub max_messages: Option<u32>,
+    // a user may post at most once per `slow_mode_secs` in this channel, admins excepted
+    pub slow_mode_secs: Option<u64>,
+    // when the channel was set up, or when its first message was sent if it was created by posting
+    pub created_at: u64,
+    pub topic: Option<String>,
+    // the user whose message created the channel, `None` when an admin set it up
+    pub created_by: Option<String>,
+    // idle past ARCHIVE_IDLE_DAYS, read-only and left out of `GET /channels` until unarchived
+    pub archived: bool,
+}
+
+impl Channel {
+    pub fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            name: row.get(0)?,
+            max_messages: row.get(1)?,
+            slow_mode_secs: row.get(2)?,
+            created_at: row.get(3)?,
+            topic: row.get(4)?,
+            created_by: row.get(5)?,
+            archived: row.get(6)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UpdateTopic {
+    // `null` or blank clears the topic
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GetChannelsQuery {
+    // only channels whose name starts with this
+    #[serde(default)]
+    q: Option<String>,
+    #[serde(default)]
+    limit: Option<u32>,
+    #[serde(default)]
+    offset: Option<u32>,
+    #[serde(default)]
+    include_archived: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CreateChannel {
+    pub name: String,
+    // who's creating it, counted against MAX_CHANNELS_PER_USER like posting to a new channel.
+    // Only admins may leave it out
+    #[serde(default)]
+    pub user_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CreateChannelQuery {
+    // `409` if the channel exists, instead of returning it
+    #[serde(default)]
+    strict: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateChannel {
+    #[serde(default)]
+    pub max_messages: Option<u32>,
+    #[serde(default)]
+    pub slow_mode_secs: Option<u64>,
+}
+
+// Channels by name, a page at a time, optionally only those starting with `q` for channel pickers.
+// Archived channels are left out unless `include_archived=true`
+async fn get_channels(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<GetChannelsQuery>,
+) -> Result<(StatusCode, Json<Vec<Channel>>), AppError> {
+    let limit = state
+        .config
+        .pagination
+        .limit(query.limit)?
+        .min(state.config.max_channels_page);
+    let offset = query.offset.unwrap_or(0);
+    // names are stored normalized, and `%` or `_` in the prefix are matched literally
+    let prefix = msg::normalize_channel(query.q.as_deref().unwrap_or_default())
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+
+    let include_archived = query.include_archived;
+
+    let channels = state
+        .call_db("get_channels", move |conn| -> Result<Vec<Channel>, Error> {
+            let mut stmt = conn
+                .prepare(&format!(
+                    "SELECT {CHANNEL_COLUMNS} FROM channels WHERE name LIKE ? || '%' ESCAPE '\\'
+                     AND (? OR NOT archived) ORDER BY name LIMIT ? OFFSET ?;"
+                ))
+                .unwrap();
+            let channels = stmt
+                .query_map(
+                    rusqlite::params![prefix, include_archived, limit, offset],
+                    Channel::from_row,
+                )
+                .unwrap()
+                .collect::<std::result::Result<Vec<Channel>, rusqlite::Error>>()
+                .unwrap();
+
+            Ok(channels)
+        })
+        .await
+        .unwrap();
+
+    Ok((StatusCode::OK, Json(channels)))
+}
+
+// Makes sure the channel exists: `201` with the new channel, or `200` with the existing one unless `strict=true`.
+// Anyone may create channels when posting can, otherwise only admins
+async fn create_channel(
+    State(state): State<Arc<AppState>>,
+    admin: Option<Admin>,
+    Query(query): Query<CreateChannelQuery>,
+    JsonOrForm(payload): JsonOrForm<CreateChannel>,
+) -> Result<(StatusCode, Json<Channel>), AppError> {
+    let is_admin = admin.is_some();
+    let name = msg::normalize_channel(&payload.name);
+    let user_id = payload.user_id.filter(|user_id| !user_id.is_empty());
+    let mut errors = ValidationError::default();
+    if name.is_empty() {
+        errors.add("name", "must not be empty");
+    }
+    if user_id.is_none() && !is_admin {
+        errors.add("user_id", "must not be empty");
+    }
+    errors.check()?;
+
+    let auto_create_channels = state.config.auto_create_channels;
+    let max_channels_per_user = state.config.max_channels_per_user;
+    let now = msg::now_millis();
+    let (channel, created) = state
+        .call_db("create_channel", move |conn| {
+            let existing = conn
+                .query_row(
+                    &format!("SELECT {CHANNEL_COLUMNS} FROM channels WHERE name = ?"),
+                    [&name],
+                    Channel::from_row,
+                )
+                .optional()
+                .unwrap();
+            if let Some(existing) = existing {
+                return Ok((existing, false));
+            }
+
+            if !is_admin && !auto_create_channels {
+                return Err(AppError::new(
+                    StatusCode::FORBIDDEN,
+                    "only admins may create channels",
+                ));
+            }
+            if let Some(max) = max_channels_per_user.filter(|_| !is_admin) {
+                let created: u32 = conn
+                    .query_row(
+                        "SELECT COUNT(*) FROM channels WHERE created_by = ?",
+                        [&user_id],
+                        |row| row.get(0),
+                    )
+                    .unwrap();
+                if created >= max {
+                    return Err(AppError::new(
+                        StatusCode::FORBIDDEN,
+                        format!("a user may create at most {max} channels"),
+                    ));
+                }
+            }
+
+            let channel = conn
+                .query_row(
+                    &format!(
+                        "INSERT INTO channels (name, created_at, created_by) VALUES (?, ?, ?)
+                         RETURNING {CHANNEL_COLUMNS}"
+                    ),
+                    rusqlite::params![name, now, user_id],
+                    Channel::from_row,
+                )
+                .unwrap();
+            Ok((channel, true))
+        })
+        .await?;
+
+    if created {
+        return Ok((StatusCode::CREATED, Json(channel)));
+    }
+    if query.strict {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "channel already exists",
+        ));
+    }
+    Ok((StatusCode::OK, Json(channel)))
+}
+
+async fn update_channel(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(payload): Json<UpdateChannel>,
+) -> Result<(StatusCode, Json<Channel>), AppError> {
+    let mut errors = ValidationError::default();
+    if payload.max_messages == Some(0) {
+        errors.add("max_messages", "must be at least 1");
+    }
+    if payload
+        .slow_mode_secs
+        .is_some_and(|secs| secs > MAX_SLOW_MODE_SECS)
+    {
+        errors.add(
+            "slow_mode_secs",
+            format!("must be at most {MAX_SLOW_MODE_SECS}"),
+        );
+    }
+    errors.check()?;
+
+    let name = msg::normalize_channel(&name);
+    let now = msg::now_millis();
+
+    // Create the channel row or overwrite its settings, an existing channel keeps its `created_at`, topic,
+    // creator and archived state
+    let channel = state
+        .call_db("update_channel", move |conn| {
+            conn.query_row(
+                &format!(
+                    "INSERT INTO channels (name, max_messages, slow_mode_secs, created_at) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT(name) DO UPDATE SET max_messages = ?2, slow_mode_secs = ?3
+                     RETURNING {CHANNEL_COLUMNS}"
+                ),
+                rusqlite::params![name, payload.max_messages, payload.slow_mode_secs, now],
+                Channel::from_row,
+            )
+            .unwrap()
+        })
+        .await;
+
+    Ok((StatusCode::OK, Json(channel)))
+}
+
+// Sets the channel's topic, creating the channel if needed, and tells connected sockets about it
+async fn update_topic(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(payload): Json<UpdateTopic>,
+) -> Result<(StatusCode, Json<Channel>), AppError> {
+    let topic = payload
+        .topic
+        .map(|topic| topic.trim().to_string())
+        .filter(|topic| !topic.is_empty());
+    let mut errors = ValidationError::default();
+    if topic
+        .as_ref()
+        .is_some_and(|topic| topic.chars().count() > MAX_TOPIC_LEN)
+    {
+        errors.add(
+            "topic",
+            format!("must be at most {MAX_TOPIC_LEN} characters"),
+        );
+    }
+    errors.check()?;
+
+    let name = msg::normalize_channel(&name);
+    let now = msg::now_millis();
+    let channel = state
+        .call_db("update_topic", move |conn| {
+            conn.query_row(
+                &format!(
+                    "INSERT INTO channels (name, topic, created_at) VALUES (?1, ?2, ?3)
+                     ON CONFLICT(name) DO UPDATE SET topic = ?2
+                     RETURNING {CHANNEL_COLUMNS}"
+                ),
+                rusqlite::params![name, topic, now],
+                Channel::from_row,
+            )
+            .unwrap()
+        })
+        .await;
+
+    state.broadcast(ws::ServerFrame::Topic {
+        channel: channel.name.clone(),
+        topic: channel.topic.clone(),
+    });
+
+    Ok((StatusCode::OK, Json(channel)))
+}
+
+// Lets an archived channel be posted to again, it's archived once more if it stays idle for another period
+async fn unarchive_channel(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<(StatusCode, Json<Channel>), AppError> {
+    let name = msg::normalize_channel(&name);
+    let now = msg::now_millis();
+    let channel = state
+        .call_db("unarchive_channel", move |conn| {
+            conn.query_row(
+                &format!(
+                    "UPDATE channels SET archived = 0, unarchived_at = ? WHERE name = ?
+                     RETURNING {CHANNEL_COLUMNS}"
+                ),
+                rusqlite::params![now, name],
+                Channel::from_row,
+            )
+            .optional()
+            .unwrap()
+        })
+        .await
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "channel not found"))?;
+
+    Ok((StatusCode::OK, Json(channel)))
+}
+
+// a channel with its newest message, for channel lists
+#[derive(Serialize)]
+pub struct ChannelPreview {
+    pub channel: String,
+    // `None` for a channel nothing has been posted in yet
+    pub last_message: Option<msg::Message>,
+}
+
+// Every channel with its newest message, most recently active first and empty channels last
+async fn get_previews(
+    State(state): State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<Vec<ChannelPreview>>), AppError> {
+    let (filters, params) = message_filters(msg::now_millis(), None, None, None, None);
+    let rows = state
+        .call_db(
+            "get_previews",
+            move |conn| -> Result<Vec<(String, Option<StoredMessage>)>, Error> {
+                // the message columns come first so `StoredMessage::from_row` finds them where it expects
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT {MESSAGE_COLUMNS}, c.name FROM channels c
+                         LEFT JOIN messages m ON m.id = (
+                             SELECT id FROM messages{} AND channel = c.name ORDER BY time DESC, id DESC LIMIT 1
+                         )
+                         ORDER BY m.time IS NULL, m.time DESC, m.id DESC, c.name;",
+                        where_clause(&filters)
+                    ))
+                    .unwrap();
+                let rows = stmt
+                    .query_map(rusqlite::params_from_iter(&params), |row| {
+                        let last_message = match row.get::<_, Option<String>>(0)? {
+                            Some(_) => Some(StoredMessage::from_row(row)?),
+                            None => None,
+                        };
+                        Ok((row.get("name")?, last_message))
+                    })
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<(String, Option<StoredMessage>)>, rusqlite::Error>>()
+                    .unwrap();
+
+                Ok(rows)
+            },
+        )
+        .await
+        .unwrap();
+
+    let previews = rows
+        .into_iter()
+        .map(|(channel, last_message)| {
+            Ok(ChannelPreview {
+                channel,
+                last_message: last_message.map(|stored| stored.open(&state)).transpose()?,
+            })
+        })
+        .collect::<Result<Vec<ChannelPreview>, AppError>>()?;
+
+    Ok((StatusCode::OK, Json(previews)))
+}
+
+// someone who has posted in a channel
+#[derive(Serialize)]
+pub struct Participant {
+    pub user_id: String,
+    pub username: String,
+    pub last_message_time: u64,
+}
+
+#[derive(Deserialize)]
+struct ParticipantsQuery {
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+// Users who have posted in the channel, most recently active first
+async fn get_participants(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<ParticipantsQuery>,
+) -> Result<(StatusCode, Json<Vec<Participant>>), AppError> {
+    let channel = msg::normalize_channel(&name);
+    let limit = state.config.pagination.limit(query.limit)?;
+
+    let (filters, mut params) =
+        message_filters(msg::now_millis(), Some(&channel), None, None, None);
+    params.push(Value::Integer(limit.into()));
+    let participants = state
+        .call_db(
+            "get_participants",
+            move |conn| -> Result<Vec<Participant>, Error> {
+                // with MAX(), SQLite takes the bare `username` from the user's latest message
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT user_id, username, MAX(time) last FROM messages{}
+                         GROUP BY user_id ORDER BY last DESC, user_id LIMIT ?;",
+                        where_clause(&filters)
+                    ))
+                    .unwrap();
+                let participants = stmt
+                    .query_map(rusqlite::params_from_iter(&params), |row| {
+                        Ok(Participant {
+                            user_id: row.get(0)?,
+                            username: row.get(1)?,
+                            last_message_time: row.get(2)?,
+                        })
+                    })
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<Participant>, rusqlite::Error>>()
+                    .unwrap();
+
+                Ok(participants)
+            },
+        )
+        .await
+        .unwrap();
+
+    Ok((StatusCode::OK, Json(participants)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn update(
+        max_messages: Option<u32>,
+        slow_mode_secs: Option<u64>,
+    ) -> Result<Channel, AppError> {
+        let state = crate::state::test_state(|_| {});
+        let (_, Json(channel)) = update_channel(
+            Admin,
+            State(state),
+            Path("general".to_string()),
+            Json(UpdateChannel {
+                max_messages,
+                slow_mode_secs,
+            }),
+        )
+        .await?;
+        Ok(channel)
+    }
+
+    #[tokio::test]
+    async fn update_channel_rejects_out_of_range_settings() {
+        let err = update(Some(0), None).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        let err = update(None, Some(MAX_SLOW_MODE_SECS + 1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let channel = update(Some(1), Some(MAX_SLOW_MODE_SECS)).await.unwrap();
+        assert_eq!(channel.max_messages, Some(1));
+        assert_eq!(channel.slow_mode_secs, Some(MAX_SLOW_MODE_SECS));
+    }
+
+    #[tokio::test]
+    async fn topic_too_long_is_a_validation_error() {
+        let state = crate::state::test_state(|_| {});
+        let Err(err) = update_topic(
+            Admin,
+            State(state),
+            Path("general".to_string()),
+            Json(UpdateTopic {
+                topic: Some("a".repeat(MAX_TOPIC_LEN + 1)),
+            }),
+        )
+        .await
+        else {
+            panic!("a topic over the limit was stored");
+        };
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.field_errors[0].field, "topic");
+    }
+}
@@ -0,0 +1,49 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::state::AppState;
+
+// Extractor for the address of whoever made the request. Behind a proxy with TRUST_PROXY set that's
+// read from the proxy's `X-Forwarded-For` or `X-Real-IP`, otherwise it's the peer of the connection
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        // the headers are the client's to write unless a proxy we trust overwrites them
+        if state.config.trust_proxy {
+            if let Some(ip) = forwarded_ip(&parts.headers) {
+                return Ok(ClientIp(ip));
+            }
+        }
+
+        let ConnectInfo(addr) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .copied()
+            .expect("the app is served with connect info");
+        Ok(ClientIp(addr.ip()))
+    }
+}
+
+// The last `X-Forwarded-For` hop, the one our proxy appended, falling back to `X-Real-IP`
+fn forwarded_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    header("x-forwarded-for")
+        .and_then(|hops| hops.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .or_else(|| header("x-real-ip").and_then(|ip| ip.trim().parse().ok()))
+}
@@ -0,0 +1,36 @@
+// zstd level used for stored text, the default trades little CPU for most of the savings
+const LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+// Compresses `text` with zstd, `None` when that wouldn't make it any smaller
+pub fn compress(text: &[u8]) -> Option<Vec<u8>> {
+    let compressed = zstd::encode_all(text, LEVEL).unwrap();
+    (compressed.len() < text.len()).then_some(compressed)
+}
+
+// Returns `None` when `compressed` isn't a valid zstd frame
+pub fn decompress(compressed: &[u8]) -> Option<Vec<u8>> {
+    zstd::decode_all(compressed).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let text = "the same few words over and over ".repeat(50);
+        let compressed = compress(text.as_bytes()).unwrap();
+        assert!(compressed.len() < text.len());
+        assert_eq!(decompress(&compressed).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn text_too_short_to_shrink_is_left_alone() {
+        assert_eq!(compress(b"hi"), None);
+    }
+
+    #[test]
+    fn garbage_is_not_decompressed() {
+        assert_eq!(decompress(b"not zstd"), None);
+    }
+}
@@ -0,0 +1,410 @@
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::Serialize;
+use std::{env, str::FromStr, sync::Arc, time::Duration};
+
+use crate::{error::AppError, filter::FilterMode, msg, state::AppState};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/config", get(get_config))
+}
+
+// Runtime settings read from the environment (or `.env`) at startup
+pub struct Config {
+    // bearer token that grants access to admin-only endpoints, admin endpoints are disabled when unset
+    pub admin_token: Option<String>,
+    // origins allowed to make cross-origin requests, any origin when CORS_ORIGINS is unset or `*`
+    pub cors_origins: Vec<String>,
+    // let browsers send cookies and auth headers cross-origin, needs `cors_origins`
+    pub cors_allow_credentials: bool,
+    // how long browsers may cache a preflight response, not sent with CORS_MAX_AGE_SECS=0
+    pub cors_max_age: Option<Duration>,
+    // serves the `/admin` endpoints on this port only, rather than alongside the public API on PORT
+    pub internal_port: Option<u16>,
+    // limits are in characters, not bytes
+    pub max_message_len: usize,
+    pub max_username_len: usize,
+    // size limit on a message's `metadata`, in bytes of serialized JSON
+    pub max_metadata_len: usize,
+    // longest `ttl_secs` a message may ask for
+    pub max_ttl_secs: u64,
+    // largest WebSocket message a client may send in bytes, a socket sending a bigger one is closed
+    pub max_ws_message_len: usize,
+    // `POST /users` without a username gets a generated `guest-` one instead of a validation error
+    pub allow_anon_users: bool,
+    // base64 AES-256 key used to encrypt message text at rest, text is stored as plaintext when unset
+    pub message_encryption_key: Option<String>,
+    // db calls slower than this are logged, disabled with SLOW_QUERY_MS=0
+    pub slow_query_threshold: Option<Duration>,
+    // skip broadcasting a socket's own messages back to it, the sender renders from the ack instead.
+    // The same user's messages from other sockets or REST still arrive
+    pub suppress_echo: bool,
+    // how many replies deep a thread may go, unlimited unless MAX_REPLY_DEPTH is set
+    pub max_reply_depth: Option<u32>,
+    // per user, across all channels and instances, unlimited unless MAX_MESSAGES_PER_MINUTE is set
+    pub max_messages_per_minute: Option<u32>,
+    // per bot integration token, unlimited with BOT_MESSAGES_PER_MINUTE=0
+    pub bot_messages_per_minute: Option<u32>,
+    // a user's message repeating their last one in the channel within this window is rejected,
+    // disabled unless DUPLICATE_WINDOW_SECS is set
+    pub duplicate_window: Option<Duration>,
+    // record every WebSocket session's user, address and duration, for looking into abuse
+    pub log_connections: bool,
+    // concurrent WebSocket connections, unlimited when MAX_CONNECTIONS is unset or 0
+    pub max_connections: Option<usize>,
+    // how often sockets are sent a `heartbeat` frame, disabled with HEARTBEAT_SECS=0
+    pub heartbeat_interval: Option<Duration>,
+    // how long a socket connected with `?resend_unacked=true` has to ack a message before it's resent
+    pub ack_timeout: Duration,
+    // take the client's address from `X-Forwarded-For`/`X-Real-IP`, only safe behind a proxy that sets them
+    pub trust_proxy: bool,
+    // where messages with a blank channel go, unless REJECT_EMPTY_CHANNEL is set
+    pub default_channel: String,
+    pub reject_empty_channel: bool,
+    // channels without a message for this long are archived, never with ARCHIVE_IDLE_DAYS=0
+    pub archive_idle: Option<Duration>,
+    // how long deletes are remembered for `GET /messages/delta`, clients that last synced before that reload instead
+    pub delta_retention: Duration,
+    // posting to a channel without a row creates it, otherwise only channels set up with PUT /channels/:name exist
+    pub auto_create_channels: bool,
+    // page sizes for every list endpoint
+    pub pagination: Pagination,
+    // most channels one `GET /channels` page returns, on top of the pagination limit
+    pub max_channels_page: u32,
+    // how many channels a non-admin user may create by posting, unlimited unless MAX_CHANNELS_PER_USER is set
+    pub max_channels_per_user: Option<u32>,
+    // file of blocked words, and whether messages containing them are rejected or masked
+    pub blocklist_path: Option<String>,
+    pub blocklist_mode: FilterMode,
+    // what's done with message text containing control characters, see `filter::strip_control_chars`
+    pub control_chars: ControlCharPolicy,
+    // how far a message's `time` may be from the server's clock, any time is accepted unless MAX_TIME_SKEW_SECS is set
+    pub max_time_skew: Option<Duration>,
+    pub time_skew: TimeSkewPolicy,
+    pub migrate_mode: MigrateMode,
+    // after a shutdown signal `/ready` fails for this long before the server stops accepting requests,
+    // giving the load balancer time to take it out of rotation
+    pub shutdown_drain: Duration,
+    // newest messages kept in memory per channel to serve first pages from, disabled with RECENT_MESSAGES=0
+    pub recent_messages: Option<usize>,
+    // channels whose recent messages are kept, the least recently read make way for others
+    pub recent_channels: usize,
+    // message text longer than this many bytes is stored zstd compressed, disabled with COMPRESS_TEXT_OVER=0
+    pub compress_text_over: Option<usize>,
+    // socket messages waiting to be written, unset or 0 has each socket write its own messages as they arrive
+    pub ingest_queue: Option<usize>,
+    // the writer takes up to `ingest_batch_size` queued messages into one transaction,
+    // waiting at most `ingest_batch_window` after the first for more to arrive
+    pub ingest_batch_size: usize,
+    pub ingest_batch_window: Duration,
+    pub ingest_when_full: QueueFullPolicy,
+}
+
+// How many items a list endpoint returns when the request doesn't say, and the most it will
+#[derive(Clone, Copy)]
+pub struct Pagination {
+    pub default_limit: u32,
+    pub max_limit: u32,
+}
+
+impl Pagination {
+    // The page size for a request's `limit`, capped at `max_limit`
+    pub fn limit(&self, requested: Option<u32>) -> Result<u32, AppError> {
+        match requested {
+            None => Ok(self.default_limit),
+            Some(0) => Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "limit must be at least 1",
+            )),
+            Some(limit) => Ok(limit.min(self.max_limit)),
+        }
+    }
+}
+
+// How startup treats pending migrations
+#[derive(Clone, Copy)]
+pub enum MigrateMode {
+    // run them
+    Apply,
+    // fail to start unless they've already been run
+    Validate,
+    // assume the schema is current
+    Skip,
+}
+
+impl FromStr for MigrateMode {
+    type Err = ();
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "apply" => Ok(MigrateMode::Apply),
+            "validate" => Ok(MigrateMode::Validate),
+            "skip" => Ok(MigrateMode::Skip),
+            _ => Err(()),
+        }
+    }
+}
+
+// What's done with a message whose text contains control characters
+#[derive(Clone, Copy)]
+pub enum ControlCharPolicy {
+    // store the text without them
+    Strip,
+    // turn the message away with a validation error
+    Reject,
+}
+
+impl FromStr for ControlCharPolicy {
+    type Err = ();
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "strip" => Ok(ControlCharPolicy::Strip),
+            "reject" => Ok(ControlCharPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+// What's done with a message whose `time` is further from the server's clock than `max_time_skew`
+#[derive(Clone, Copy)]
+pub enum TimeSkewPolicy {
+    // store it with the server's time instead
+    Clamp,
+    // turn the message away with a validation error
+    Reject,
+}
+
+impl FromStr for TimeSkewPolicy {
+    type Err = ();
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "clamp" => Ok(TimeSkewPolicy::Clamp),
+            "reject" => Ok(TimeSkewPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+// What a socket sending a message does when the ingestion queue is full
+#[derive(Clone, Copy)]
+pub enum QueueFullPolicy {
+    // wait for room, which stops reading that socket's frames in the meantime
+    Wait,
+    // turn the message away with an error frame, the client may resend it
+    Reject,
+}
+
+impl FromStr for QueueFullPolicy {
+    type Err = ();
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "wait" => Ok(QueueFullPolicy::Wait),
+            "reject" => Ok(QueueFullPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            admin_token: env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            cors_origins: env::var("CORS_ORIGINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty() && origin != "*")
+                .collect(),
+            cors_allow_credentials: parse_env("CORS_ALLOW_CREDENTIALS", false)?,
+            cors_max_age: match parse_env("CORS_MAX_AGE_SECS", 0)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            internal_port: match parse_env("INTERNAL_PORT", 0)? {
+                0 => None,
+                port => Some(port),
+            },
+            max_message_len: parse_env("MAX_MESSAGE_LEN", 2000)?,
+            max_username_len: parse_env("MAX_USERNAME_LEN", 32)?,
+            max_metadata_len: parse_env("MAX_METADATA_BYTES", 4096)?,
+            max_ttl_secs: parse_env("MAX_TTL_SECS", 365 * 24 * 60 * 60)?.max(1),
+            max_ws_message_len: parse_env("MAX_WS_MESSAGE_BYTES", 64 * 1024)?,
+            allow_anon_users: parse_env("ALLOW_ANON_USERS", false)?,
+            message_encryption_key: env::var("MESSAGE_ENCRYPTION_KEY")
+                .ok()
+                .filter(|key| !key.is_empty()),
+            slow_query_threshold: match parse_env("SLOW_QUERY_MS", 100)? {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            suppress_echo: parse_env("SUPPRESS_ECHO", false)?,
+            max_reply_depth: match parse_env("MAX_REPLY_DEPTH", 0)? {
+                0 => None,
+                depth => Some(depth),
+            },
+            max_messages_per_minute: match parse_env("MAX_MESSAGES_PER_MINUTE", 0)? {
+                0 => None,
+                max => Some(max),
+            },
+            bot_messages_per_minute: match parse_env("BOT_MESSAGES_PER_MINUTE", 60)? {
+                0 => None,
+                max => Some(max),
+            },
+            duplicate_window: match parse_env("DUPLICATE_WINDOW_SECS", 0)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            log_connections: parse_env("LOG_CONNECTIONS", false)?,
+            max_connections: match parse_env("MAX_CONNECTIONS", 0)? {
+                0 => None,
+                max => Some(max),
+            },
+            heartbeat_interval: match parse_env("HEARTBEAT_SECS", 30)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            ack_timeout: Duration::from_secs(parse_env("ACK_TIMEOUT_SECS", 10)?.max(1)),
+            trust_proxy: parse_env("TRUST_PROXY", false)?,
+            default_channel: msg::normalize_channel(&parse_env(
+                "DEFAULT_CHANNEL",
+                String::from("main"),
+            )?),
+            reject_empty_channel: parse_env("REJECT_EMPTY_CHANNEL", false)?,
+            auto_create_channels: parse_env("AUTO_CREATE_CHANNELS", true)?,
+            archive_idle: match parse_env("ARCHIVE_IDLE_DAYS", 0)? {
+                0 => None,
+                days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+            },
+            pagination: {
+                let max_limit = parse_env("MAX_PAGE_SIZE", 100)?.max(1);
+                Pagination {
+                    default_limit: parse_env("DEFAULT_PAGE_SIZE", 100)?.clamp(1, max_limit),
+                    max_limit,
+                }
+            },
+            max_channels_page: parse_env("MAX_CHANNELS_PAGE", 100)?.max(1),
+            delta_retention: Duration::from_secs(
+                parse_env("DELTA_RETENTION_DAYS", 7u64)?.max(1) * 24 * 60 * 60,
+            ),
+            max_channels_per_user: match parse_env("MAX_CHANNELS_PER_USER", 0)? {
+                0 => None,
+                max => Some(max),
+            },
+            blocklist_path: env::var("BLOCKLIST_PATH")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            blocklist_mode: parse_env("BLOCKLIST_MODE", FilterMode::Mask)?,
+            control_chars: parse_env("CONTROL_CHARS", ControlCharPolicy::Strip)?,
+            max_time_skew: match parse_env("MAX_TIME_SKEW_SECS", 0)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            time_skew: parse_env("TIME_SKEW", TimeSkewPolicy::Clamp)?,
+            migrate_mode: parse_env("MIGRATE_MODE", MigrateMode::Apply)?,
+            shutdown_drain: Duration::from_secs(parse_env("SHUTDOWN_DRAIN_SECS", 5)?),
+            recent_messages: match parse_env("RECENT_MESSAGES", 100)? {
+                0 => None,
+                count => Some(count),
+            },
+            recent_channels: parse_env("RECENT_CHANNELS", 100)?,
+            compress_text_over: match parse_env("COMPRESS_TEXT_OVER", 0)? {
+                0 => None,
+                bytes => Some(bytes),
+            },
+            ingest_queue: match parse_env("INGEST_QUEUE", 0)? {
+                0 => None,
+                capacity => Some(capacity),
+            },
+            ingest_batch_size: parse_env("INGEST_BATCH_SIZE", 100)?.max(1),
+            ingest_batch_window: Duration::from_millis(parse_env("INGEST_BATCH_WINDOW_MS", 5)?),
+            ingest_when_full: parse_env("INGEST_WHEN_FULL", QueueFullPolicy::Wait)?,
+        })
+    }
+}
+
+// Reads `key` from the env, falling back to `default` when unset, an unparsable value is an error naming `key`
+fn parse_env<T: FromStr>(key: &str, default: T) -> Result<T, String> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{key} has an invalid value: {value}")),
+        Err(_) => Ok(default),
+    }
+}
+
+// the limits clients should validate against before sending
+#[derive(Serialize)]
+struct ConfigInfo {
+    server_time: u64,
+    max_message_len: usize,
+    max_username_len: usize,
+    max_metadata_bytes: usize,
+    max_ttl_secs: u64,
+    max_ws_message_bytes: usize,
+    // the most a list endpoint returns, however large a `limit` is asked for
+    max_page_size: u32,
+    // clients that go two intervals without a heartbeat should reconnect, `None` when heartbeats are off
+    heartbeat_secs: Option<u64>,
+    // sockets connected with `?resend_unacked=true` get a message once more if it isn't acked in time
+    ack_timeout_secs: u64,
+    // how far a message's `time` may be from `server_time`, `None` when any time is accepted
+    max_time_skew_secs: Option<u64>,
+    // messages a user may send per minute, `None` when unlimited
+    max_messages_per_minute: Option<u32>,
+    // repeating one's last message in a channel within this many seconds is rejected, `None` when allowed
+    duplicate_window_secs: Option<u64>,
+    // how many replies deep a thread may go, `None` when unlimited
+    max_reply_depth: Option<u32>,
+    // channels a non-admin user may create by posting, `None` when unlimited
+    max_channels_per_user: Option<u32>,
+}
+
+async fn get_config(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ConfigInfo>) {
+    let info = ConfigInfo {
+        server_time: msg::now_millis(),
+        max_message_len: state.config.max_message_len,
+        max_username_len: state.config.max_username_len,
+        max_metadata_bytes: state.config.max_metadata_len,
+        max_ttl_secs: state.config.max_ttl_secs,
+        max_ws_message_bytes: state.config.max_ws_message_len,
+        max_page_size: state.config.pagination.max_limit,
+        heartbeat_secs: state
+            .config
+            .heartbeat_interval
+            .map(|interval| interval.as_secs()),
+        ack_timeout_secs: state.config.ack_timeout.as_secs(),
+        max_time_skew_secs: state.config.max_time_skew.map(|skew| skew.as_secs()),
+        max_messages_per_minute: state.config.max_messages_per_minute,
+        duplicate_window_secs: state.config.duplicate_window.map(|window| window.as_secs()),
+        max_reply_depth: state.config.max_reply_depth,
+        max_channels_per_user: state.config.max_channels_per_user,
+    };
+
+    (StatusCode::OK, Json(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unparsable_env_value_is_an_error_naming_the_variable() {
+        // a variable no setting reads, so other tests' configs aren't affected
+        let key = "PARSE_ENV_TEST_VALUE";
+        assert_eq!(parse_env(key, 5u32), Ok(5));
+        env::set_var(key, "12");
+        assert_eq!(parse_env(key, 5u32), Ok(12));
+        env::set_var(key, "twelve");
+        assert_eq!(
+            parse_env(key, 5u32),
+            Err(String::from(
+                "PARSE_ENV_TEST_VALUE has an invalid value: twelve"
+            ))
+        );
+        env::remove_var(key);
+    }
+}
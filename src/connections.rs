@@ -0,0 +1,165 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{watch, Notify};
+
+use crate::{msg, state::AppState};
+
+// A WebSocket session as recorded with LOG_CONNECTIONS
+#[derive(Serialize)]
+pub struct Connection {
+    pub id: String,
+    // from `?user_id=`, or whoever the socket sent chat messages as
+    pub user_id: Option<String>,
+    pub ip: String,
+    pub user_agent: String,
+    pub connected_at: u64,
+    // `None` while the socket is open, or if the server died before it closed
+    pub disconnected_at: Option<u64>,
+}
+
+// A socket open on this instance, as `GET /admin/connections/live` lists it
+#[derive(Serialize)]
+pub struct LiveConnection {
+    // the same id as in the connection log, when LOG_CONNECTIONS is set
+    pub id: String,
+    pub user_id: Option<String>,
+    pub ip: String,
+    pub user_agent: String,
+    pub connected_at: u64,
+}
+
+struct LiveSocket {
+    ip: IpAddr,
+    user_agent: String,
+    connected_at: u64,
+    // the user the socket connected or last sent a message as
+    user_id: watch::Receiver<Option<String>>,
+    // the socket closes when notified
+    kick: Arc<Notify>,
+}
+
+// The sockets open on this instance, added once a socket is set up and removed as it closes
+#[derive(Default)]
+pub struct LiveConnections {
+    sockets: Mutex<HashMap<String, LiveSocket>>,
+}
+
+impl LiveConnections {
+    pub fn add(
+        &self,
+        id: String,
+        ip: IpAddr,
+        user_agent: String,
+        user_id: watch::Receiver<Option<String>>,
+        kick: Arc<Notify>,
+    ) {
+        let socket = LiveSocket {
+            ip,
+            user_agent,
+            connected_at: msg::now_millis(),
+            user_id,
+            kick,
+        };
+        self.sockets.lock().unwrap().insert(id, socket);
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.sockets.lock().unwrap().remove(id);
+    }
+
+    // Oldest first
+    pub fn list(&self) -> Vec<LiveConnection> {
+        let mut connections: Vec<LiveConnection> = self
+            .sockets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, socket)| LiveConnection {
+                id: id.clone(),
+                user_id: socket.user_id.borrow().clone(),
+                ip: socket.ip.to_string(),
+                user_agent: socket.user_agent.clone(),
+                connected_at: socket.connected_at,
+            })
+            .collect();
+        connections.sort_by(|a, b| (a.connected_at, &a.id).cmp(&(b.connected_at, &b.id)));
+        connections
+    }
+
+    // Tells the socket to close, returning whether it's open here
+    pub fn kick(&self, id: &str) -> bool {
+        match self.sockets.lock().unwrap().get(id) {
+            Some(socket) => {
+                socket.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Records a socket opening, returning the id to record its close under
+pub async fn record_connect(
+    state: &AppState,
+    user_id: Option<String>,
+    ip: IpAddr,
+    user_agent: String,
+) -> String {
+    let id = uuidv7::create();
+    let id_copy = id.clone();
+    let connected_at = msg::now_millis();
+    state
+        .call_db("record_connect", move |conn| {
+            conn.execute(
+                "INSERT INTO connections (id, user_id, ip, user_agent, connected_at) VALUES (?, ?, ?, ?, ?)",
+                rusqlite::params![id_copy, user_id, ip.to_string(), user_agent, connected_at],
+            )
+            .unwrap();
+        })
+        .await;
+    id
+}
+
+// Records the socket closing, with the user it ended up sending as if it didn't say on connect
+pub async fn record_disconnect(state: &AppState, id: String, user_id: Option<String>) {
+    let disconnected_at = msg::now_millis();
+    state
+        .call_db("record_disconnect", move |conn| {
+            conn.execute(
+                "UPDATE connections SET disconnected_at = ?, user_id = COALESCE(user_id, ?) WHERE id = ?",
+                rusqlite::params![disconnected_at, user_id, id],
+            )
+            .unwrap();
+        })
+        .await;
+}
+
+// The newest sessions, only the user's when `user_id` is given
+pub async fn recent(state: &AppState, user_id: Option<String>, limit: u32) -> Vec<Connection> {
+    state
+        .call_db("recent_connections", move |conn| {
+            conn.prepare(
+                "SELECT id, user_id, ip, user_agent, connected_at, disconnected_at FROM connections
+                 WHERE ?1 IS NULL OR user_id = ?1 ORDER BY connected_at DESC, id DESC LIMIT ?2;",
+            )
+            .unwrap()
+            .query_map(rusqlite::params![user_id, limit], |row| {
+                Ok(Connection {
+                    id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    ip: row.get(2)?,
+                    user_agent: row.get(3)?,
+                    connected_at: row.get(4)?,
+                    disconnected_at: row.get(5)?,
+                })
+            })
+            .unwrap()
+            .collect::<Result<Vec<Connection>, rusqlite::Error>>()
+            .unwrap()
+        })
+        .await
+}
@@ -0,0 +1,52 @@
+use axum::http::{header, HeaderName, HeaderValue};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::config::Config;
+
+// Response headers browsers let scripts read, listed out because credentialed mode can't use a wildcard
+const EXPOSED_HEADERS: [HeaderName; 5] = [
+    header::LINK,
+    header::RETRY_AFTER,
+    HeaderName::from_static("x-next-cursor"),
+    HeaderName::from_static("x-request-id"),
+    HeaderName::from_static("x-total-count"),
+];
+
+// The CORS layer for CORS_ORIGINS, CORS_ALLOW_CREDENTIALS and CORS_MAX_AGE_SECS,
+// or why they can't be used together
+pub fn layer(config: &Config) -> Result<CorsLayer, String> {
+    if config.cors_origins.is_empty() {
+        // browsers refuse credentials from a server that lets any origin in
+        if config.cors_allow_credentials {
+            return Err(String::from(
+                "CORS_ALLOW_CREDENTIALS needs CORS_ORIGINS set to a list of origins, not any origin",
+            ));
+        }
+        let layer = CorsLayer::permissive();
+        return Ok(match config.cors_max_age {
+            Some(max_age) => layer.max_age(max_age),
+            None => layer,
+        });
+    }
+
+    let origins = config
+        .cors_origins
+        .iter()
+        .map(|origin| {
+            HeaderValue::from_str(origin)
+                .map_err(|_| format!("CORS_ORIGINS has an invalid origin: {origin}"))
+        })
+        .collect::<Result<Vec<HeaderValue>, String>>()?;
+
+    // mirroring stands in for the wildcards, which can't be combined with credentials either
+    let layer = CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request())
+        .expose_headers(EXPOSED_HEADERS)
+        .allow_credentials(config.cors_allow_credentials);
+    Ok(match config.cors_max_age {
+        Some(max_age) => layer.max_age(max_age),
+        None => layer,
+    })
+}
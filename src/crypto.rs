@@ -0,0 +1,56 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+
+// An unguessable 256 bit token, url-safe so it can go in a query string
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// `len` random bytes as lowercase hex, for short ids people get to see
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// Encrypts message text at rest with a server-held AES-256-GCM key
+#[derive(Clone)]
+pub struct TextCipher(Aes256Gcm);
+
+impl TextCipher {
+    // `key` is the base64 encoding of a 32 byte key
+    pub fn from_base64_key(key: &str) -> Self {
+        let key = STANDARD
+            .decode(key)
+            .expect("MESSAGE_ENCRYPTION_KEY must be valid base64.");
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .expect("MESSAGE_ENCRYPTION_KEY must decode to 32 bytes.");
+        Self(cipher)
+    }
+
+    // Returns the base64 ciphertext and the random nonce it was sealed with
+    pub fn encrypt(&self, plaintext: &[u8]) -> (String, Vec<u8>) {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.0.encrypt(&nonce, plaintext).unwrap();
+        (STANDARD.encode(ciphertext), nonce.to_vec())
+    }
+
+    // Returns `None` when the ciphertext was sealed with another key or has been tampered with
+    pub fn decrypt(&self, ciphertext: &str, nonce: &[u8]) -> Option<Vec<u8>> {
+        if nonce.len() != 12 {
+            return None;
+        }
+        let ciphertext = STANDARD.decode(ciphertext).ok()?;
+        self.0
+            .decrypt(Nonce::from_slice(nonce), ciphertext.as_slice())
+            .ok()
+    }
+}
@@ -0,0 +1,83 @@
+use axum::{http::StatusCode, Error};
+use rusqlite::{types::Value, OptionalExtension};
+
+use crate::{
+    error::AppError,
+    messages::{message_filters, where_clause, StoredMessage, MESSAGE_COLUMNS},
+    msg,
+    state::AppState,
+};
+
+// most messages resent to a reconnecting socket, anything older has to be fetched over REST
+pub const MAX_REPLAY: u32 = 500;
+
+// Records `message_id` as delivered to `user_id` in its channel, unless a newer message was already acked there
+pub async fn record_ack(
+    state: &AppState,
+    user_id: String,
+    message_id: String,
+) -> Result<(), AppError> {
+    state
+        .call_db("record_ack", move |conn| {
+            let message = conn
+                .query_row(
+                    "SELECT channel, time FROM messages WHERE id = ?",
+                    [&message_id],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)),
+                )
+                .optional()
+                .unwrap();
+            let Some((channel, time)) = message else {
+                return Err(AppError::new(StatusCode::NOT_FOUND, "message not found"));
+            };
+
+            // acks can arrive out of order, only move the position forward in `time, id` order
+            conn.execute(
+                "INSERT INTO deliveries (user_id, channel, message_id, time) VALUES (?, ?, ?, ?)
+                 ON CONFLICT(user_id, channel) DO UPDATE SET message_id = excluded.message_id, time = excluded.time
+                 WHERE (excluded.time, excluded.message_id) > (deliveries.time, deliveries.message_id)",
+                rusqlite::params![user_id, channel, message_id, time],
+            )
+            .unwrap();
+
+            Ok(())
+        })
+        .await
+}
+
+// Messages newer than the last one `user_id` acked, in every channel it has acked something in, oldest first
+pub async fn undelivered(state: &AppState, user_id: String) -> Result<Vec<msg::Message>, AppError> {
+    let (filters, mut params) = message_filters(msg::now_millis(), None, None, None, None);
+    params.push(Value::Text(user_id));
+    params.push(Value::Integer(MAX_REPLAY.into()));
+    let stored = state
+        .call_db(
+            "undelivered",
+            move |conn| -> Result<Vec<StoredMessage>, Error> {
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT {MESSAGE_COLUMNS} FROM messages{} AND EXISTS (
+                             SELECT 1 FROM deliveries d
+                             WHERE d.user_id = ? AND d.channel = messages.channel
+                             AND (messages.time, messages.id) > (d.time, d.message_id)
+                         ) ORDER BY time, id LIMIT ?;",
+                        where_clause(&filters)
+                    ))
+                    .unwrap();
+                let stored = stmt
+                    .query_map(rusqlite::params_from_iter(&params), StoredMessage::from_row)
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<StoredMessage>, rusqlite::Error>>()
+                    .unwrap();
+
+                Ok(stored)
+            },
+        )
+        .await
+        .unwrap();
+
+    stored
+        .into_iter()
+        .map(|stored| stored.open(state))
+        .collect()
+}
@@ -0,0 +1,181 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Error, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    error::AppError,
+    messages::{StoredMessage, MESSAGE_COLUMNS},
+    msg,
+    state::AppState,
+};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/messages/delta", get(get_delta))
+}
+
+#[derive(Deserialize)]
+struct DeltaQuery {
+    // milliseconds since the Unix epoch, the `until` of the client's last sync
+    since: u64,
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+// What happened to a message. Messages can't be edited, so they're only ever created or deleted
+#[derive(Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    Created {
+        message: Box<msg::Message>,
+        // the message's `time` is the sender's, changes are ordered by when the server stored it
+        #[serde(skip)]
+        received_at: u64,
+    },
+    Deleted {
+        id: String,
+        channel: String,
+        time: u64,
+    },
+}
+
+impl Change {
+    fn time(&self) -> u64 {
+        match self {
+            Change::Created { received_at, .. } => *received_at,
+            Change::Deleted { time, .. } => *time,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Delta {
+    // oldest first
+    pub changes: Vec<Change>,
+    // pass as `since` next time. The last change's time when `has_more` is set, otherwise
+    // just before the request, as changes may still be landing in the current millisecond
+    pub until: u64,
+    pub has_more: bool,
+}
+
+// Messages sent and deleted after `since`, optionally only the channel's, for clients catching up
+// incrementally. `410` when `since` is older than DELTA_RETENTION_DAYS, deletes from before then are forgotten
+async fn get_delta(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DeltaQuery>,
+) -> Result<(StatusCode, Json<Delta>), AppError> {
+    let limit = state.config.pagination.limit(query.limit)? as usize;
+    // sqlite integers are signed
+    let Ok(since) = i64::try_from(query.since) else {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "since is out of range",
+        ));
+    };
+    let retained_since =
+        msg::now_millis().saturating_sub(state.config.delta_retention.as_millis() as u64);
+    if query.since < retained_since {
+        return Err(AppError::new(
+            StatusCode::GONE,
+            "since is older than deletes are kept, reload the messages instead",
+        ));
+    }
+    let channel = query.channel.as_deref().map(msg::normalize_channel);
+
+    type CreatedRows = Vec<(StoredMessage, u64)>;
+    let (now, created, deleted) = state
+        .call_db(
+            "get_delta",
+            move |conn| -> Result<(u64, CreatedRows, Vec<Change>), Error> {
+                // taken on the db thread, so no insert lands between reading the clock and the queries.
+                // The current millisecond is left for the next sync, later inserts may still share it
+                let now = msg::now_millis();
+                // one row past the limit from each side, either can fill the page
+                let created = conn
+                    .prepare(&format!(
+                        "SELECT {MESSAGE_COLUMNS}, received_at FROM messages
+                         WHERE received_at > ?1 AND received_at < ?3 AND (?2 IS NULL OR channel = ?2)
+                           AND (expires_at IS NULL OR expires_at > ?3)
+                         ORDER BY received_at, id LIMIT ?4;"
+                    ))
+                    .unwrap()
+                    .query_map(
+                        rusqlite::params![since, channel, now, limit + 1],
+                        |row| Ok((StoredMessage::from_row(row)?, row.get(15)?)),
+                    )
+                    .unwrap()
+                    .collect::<Result<CreatedRows, rusqlite::Error>>()
+                    .unwrap();
+                let deleted = conn
+                    .prepare(
+                        "SELECT id, channel, deleted_at FROM message_tombstones
+                         WHERE deleted_at > ?1 AND deleted_at < ?3 AND (?2 IS NULL OR channel = ?2)
+                         ORDER BY deleted_at, id LIMIT ?4;",
+                    )
+                    .unwrap()
+                    .query_map(rusqlite::params![since, channel, now, limit + 1], |row| {
+                        Ok(Change::Deleted {
+                            id: row.get(0)?,
+                            channel: row.get(1)?,
+                            time: row.get(2)?,
+                        })
+                    })
+                    .unwrap()
+                    .collect::<Result<Vec<Change>, rusqlite::Error>>()
+                    .unwrap();
+
+                Ok((now, created, deleted))
+            },
+        )
+        .await
+        .unwrap();
+
+    let mut changes = created
+        .into_iter()
+        .map(|(stored, received_at)| {
+            Ok(Change::Created {
+                message: Box::new(stored.open(&state)?),
+                received_at,
+            })
+        })
+        .collect::<Result<Vec<Change>, AppError>>()?;
+    changes.extend(deleted);
+    // stable, so a message created and deleted in the same millisecond keeps its creation first
+    changes.sort_by_key(Change::time);
+
+    let has_more = changes.len() > limit;
+    let until = if has_more {
+        // the next page starts after the last change's millisecond, so the page can't end partway through one
+        let next_time = changes[limit].time();
+        changes.truncate(limit);
+        let whole_millis = changes.iter().rposition(|change| change.time() < next_time);
+        changes.truncate(whole_millis.map_or(0, |last| last + 1));
+        match changes.last() {
+            Some(last) => last.time(),
+            // more changes in a single millisecond than a page holds
+            None => {
+                return Err(AppError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "too many changes at once, raise limit or reload the messages instead",
+                ))
+            }
+        }
+    } else {
+        now - 1
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json(Delta {
+            changes,
+            until,
+            has_more,
+        }),
+    ))
+}
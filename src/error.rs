@@ -0,0 +1,104 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::time::Duration;
+
+// An error returned from a handler, rendered as `{"error": message}` with the given status,
+// or as `{"errors": [...]}` when it carries field errors from a `ValidationError`
+#[derive(Debug)]
+pub struct AppError {
+    pub status: StatusCode,
+    pub message: String,
+    pub field_errors: Vec<FieldError>,
+    // how long until the request may be retried, sent as a `Retry-After` header
+    // and as `retry_after` (seconds) and `retry_after_ms` in the body
+    pub retry_after: Option<Duration>,
+}
+
+impl AppError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            field_errors: vec![],
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(self, retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..self
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if !self.field_errors.is_empty() {
+            return (self.status, Json(json!({ "errors": self.field_errors }))).into_response();
+        }
+        match self.retry_after {
+            Some(retry_after) => {
+                // Retry-After only takes whole seconds, round up so clients never retry early
+                let millis = retry_after.as_millis() as u64;
+                let secs = millis.div_ceil(1000);
+                (
+                    self.status,
+                    [(header::RETRY_AFTER, secs.to_string())],
+                    Json(json!({
+                        "error": self.message,
+                        "retry_after": secs,
+                        "retry_after_ms": millis,
+                    })),
+                )
+                    .into_response()
+            }
+            None => (self.status, Json(json!({ "error": self.message }))).into_response(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+// Collects every problem with a payload so clients can fix them all in one go
+#[derive(Default)]
+pub struct ValidationError {
+    errors: Vec<FieldError>,
+}
+
+impl ValidationError {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field,
+            message: message.into(),
+        });
+    }
+
+    // `422 Unprocessable Entity` listing all collected errors, or `Ok` if there were none
+    pub fn check(self) -> Result<(), AppError> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        let message = self
+            .errors
+            .iter()
+            .map(|err| format!("{}: {}", err.field, err.message))
+            .collect::<Vec<String>>()
+            .join("; ");
+        Err(AppError {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            message,
+            field_errors: self.errors,
+            retry_after: None,
+        })
+    }
+}
@@ -0,0 +1,43 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{msg, state::AppState, ws};
+
+// how often expired messages are swept, reads filter them out in between
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+// Deletes messages past their `expires_at` for as long as the server runs, telling sockets about each one,
+// and drops resume tokens that can no longer be used and tombstones older than DELTA_RETENTION_DAYS
+pub async fn run(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let now = msg::now_millis();
+        let forget_before = now.saturating_sub(state.config.delta_retention.as_millis() as u64);
+        let expired = state
+            .call_db("delete_expired", move |conn| {
+                conn.execute("DELETE FROM resume_tokens WHERE expires_at <= ?", [now])
+                    .unwrap();
+                conn.execute(
+                    "DELETE FROM message_tombstones WHERE deleted_at < ?",
+                    [forget_before],
+                )
+                .unwrap();
+                conn.prepare("DELETE FROM messages WHERE expires_at <= ? RETURNING id, channel;")
+                    .unwrap()
+                    .query_map([now], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .unwrap()
+                    .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()
+                    .unwrap()
+            })
+            .await;
+
+        for (id, channel) in expired {
+            // reads skip expired messages anyway, so this can happen after the delete
+            if let Some(recent) = &state.recent {
+                recent.remove(&channel, &id);
+            }
+            state.broadcast(ws::ServerFrame::Deleted { id, channel });
+        }
+    }
+}
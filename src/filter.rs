@@ -0,0 +1,118 @@
+use std::{collections::HashSet, fs, str::FromStr};
+
+// What to do with a message containing a blocked term
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    Reject,
+    Mask,
+}
+
+impl FromStr for FilterMode {
+    type Err = ();
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "reject" => Ok(FilterMode::Reject),
+            "mask" => Ok(FilterMode::Mask),
+            _ => Err(()),
+        }
+    }
+}
+
+// Case-insensitive blocklist of single words, matched on whole words only
+// so that blocking "ass" doesn't also catch "class"
+pub struct WordFilter {
+    terms: HashSet<String>,
+    pub mode: FilterMode,
+}
+
+impl WordFilter {
+    // one term per line, blank lines and lines starting with `#` are ignored
+    pub fn load(path: &str, mode: FilterMode) -> Self {
+        let contents = fs::read_to_string(path)
+            .unwrap_or_else(|err| panic!("failed to read blocklist {path}: {err}"));
+        let terms = contents
+            .lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        Self { terms, mode }
+    }
+
+    // Returns `text` with every blocked word replaced by asterisks, and whether anything matched
+    pub fn mask(&self, text: &str) -> (String, bool) {
+        let mut masked = String::with_capacity(text.len());
+        let mut matched = false;
+        let mut word = String::new();
+
+        // words are runs of alphanumeric characters, everything else is a boundary
+        for c in text.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() {
+                if self.terms.contains(&word.to_lowercase()) {
+                    matched = true;
+                    masked.extend(word.chars().map(|_| '*'));
+                } else {
+                    masked.push_str(&word);
+                }
+                word.clear();
+            }
+            masked.push(c);
+        }
+        // drop the sentinel boundary
+        masked.pop();
+
+        (masked, matched)
+    }
+}
+
+// Returns `text` without control characters, and whether it had any. Newlines and tabs are kept, while
+// C0 and C1 controls (NUL, the ESC starting terminal escape sequences...) and the bidi controls that can
+// make text display in a different order than it reads are dropped
+pub fn strip_control_chars(text: &str) -> (String, bool) {
+    let stripped: String = text.chars().filter(|&c| !is_unsafe_control(c)).collect();
+    let had_any = stripped.len() != text.len();
+    (stripped, had_any)
+}
+
+fn is_unsafe_control(c: char) -> bool {
+    match c {
+        '\n' | '\t' => false,
+        // bidi marks, embeddings, overrides and isolates
+        '\u{061C}'
+        | '\u{200E}'
+        | '\u{200F}'
+        | '\u{202A}'..='\u{202E}'
+        | '\u{2066}'..='\u{2069}' => true,
+        // C0, DEL and C1
+        c => c.is_control(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_terminal_escapes_and_nul() {
+        let (text, had_any) = strip_control_chars("\u{1b}[31mred\u{1b}[0m\0 alert");
+        assert_eq!(text, "[31mred[0m alert");
+        assert!(had_any);
+    }
+
+    #[test]
+    fn strips_bidi_overrides_and_c1_controls() {
+        let (text, had_any) = strip_control_chars("invoice\u{202E}fdp.exe\u{2066}\u{85}\u{9b}");
+        assert_eq!(text, "invoicefdp.exe");
+        assert!(had_any);
+    }
+
+    #[test]
+    fn keeps_newlines_tabs_and_ordinary_text() {
+        let input = "line one\n\tline two, naïve café 👋";
+        assert_eq!(strip_control_chars(input), (input.to_string(), false));
+    }
+}
@@ -0,0 +1,108 @@
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use rusqlite_migration::Migrations;
+use serde::Serialize;
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use crate::{error::AppError, state::AppState};
+
+// how long `/live` waits on the db thread, a busy one is still alive
+const LIVE_DB_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/live", get(live))
+        .route("/ready", get(ready))
+        .route("/version", get(version))
+}
+
+#[derive(Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    // the migrations applied to the db, and how many this build has
+    pub schema_version: usize,
+    pub latest_schema_version: usize,
+}
+
+// Liveness, the process is up and answering until it starts shutting down. Fails once the db thread
+// is gone, nothing brings it back short of a restart
+async fn live(State(state): State<Arc<AppState>>) -> Result<&'static str, AppError> {
+    if state.shutting_down.load(Ordering::Relaxed) {
+        return Err(AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "shutting down",
+        ));
+    }
+    let ping = tokio::time::timeout(LIVE_DB_TIMEOUT, state.conn.call(|_| Ok(()))).await;
+    if let Ok(Err(err)) = ping {
+        return Err(AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("database thread stopped: {err}"),
+        ));
+    }
+    Ok("ok")
+}
+
+// Readiness, the db answers and its schema is current. Flips to `503` as soon as shutdown starts
+// so the load balancer stops sending traffic while open requests drain
+async fn ready(State(state): State<Arc<AppState>>) -> Result<&'static str, AppError> {
+    if state.shutting_down.load(Ordering::Relaxed) {
+        return Err(AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "shutting down",
+        ));
+    }
+
+    // rusqlite_migration tracks the applied migrations in `user_version`.
+    // Not `call_db`, which panics instead of failing once the db thread is gone
+    let version = state
+        .conn
+        .call(|conn| {
+            let version =
+                conn.query_row("PRAGMA user_version", [], |row| row.get::<_, usize>(0))?;
+            Ok(version)
+        })
+        .await
+        .map_err(|err| {
+            AppError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("database unavailable: {err}"),
+            )
+        })?;
+    let latest_version = crate::migrations().len();
+    if version != latest_version {
+        return Err(AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("database schema is at version {version}, expected {latest_version}"),
+        ));
+    }
+
+    Ok("ok")
+}
+
+// What's running, so clients and ops can tell which features and schema to expect
+async fn version(State(state): State<Arc<AppState>>) -> Result<Json<Version>, AppError> {
+    let schema_version = state
+        .conn
+        .call(|conn| {
+            Migrations::new(crate::migrations())
+                .current_version(conn)
+                .map(|version| usize::from(&version))
+                .map_err(|err| tokio_rusqlite::Error::Other(Box::new(err)))
+        })
+        .await
+        .map_err(|err| {
+            AppError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("failed to read schema version: {err}"),
+            )
+        })?;
+
+    Ok(Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        schema_version,
+        latest_schema_version: crate::migrations().len(),
+    }))
+}
@@ -0,0 +1,95 @@
+use axum::http::StatusCode;
+use std::sync::Arc;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+use crate::{
+    config::{Config, QueueFullPolicy},
+    error::AppError,
+    messages::{self, PreparedMessage},
+    msg,
+    state::AppState,
+};
+
+// A validated socket message waiting for the writer, and where its outcome goes
+pub struct Job {
+    prepared: PreparedMessage,
+    reply: oneshot::Sender<Result<(msg::Message, usize), AppError>>,
+}
+
+// Hands socket messages to a single writer task that stores them in batches, so a burst of messages
+// costs one transaction instead of one each. Messages are stored and broadcast in the order they're queued
+pub struct IngestQueue {
+    tx: mpsc::Sender<Job>,
+    when_full: QueueFullPolicy,
+}
+
+// The queue and the receiver `run` writes from, `None` unless INGEST_QUEUE is set
+pub fn queue(config: &Config) -> Option<(IngestQueue, mpsc::Receiver<Job>)> {
+    let (tx, rx) = mpsc::channel(config.ingest_queue?);
+    let queue = IngestQueue {
+        tx,
+        when_full: config.ingest_when_full,
+    };
+    Some((queue, rx))
+}
+
+impl IngestQueue {
+    // Queues a validated message and waits until it's been stored and broadcast. Returns the message
+    // with how many sockets it was broadcast to, like `messages::store_prepared`. Validating first means
+    // invalid messages are answered straight away, without taking up room in the queue
+    pub async fn submit(
+        &self,
+        prepared: PreparedMessage,
+    ) -> Result<(msg::Message, usize), AppError> {
+        let (reply, outcome) = oneshot::channel();
+        let job = Job { prepared, reply };
+
+        match self.when_full {
+            QueueFullPolicy::Wait => self.tx.send(job).await.map_err(|_| closed())?,
+            QueueFullPolicy::Reject => self.tx.try_send(job).map_err(|err| match err {
+                mpsc::error::TrySendError::Full(_) => AppError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "too many messages waiting to be stored, try again",
+                ),
+                mpsc::error::TrySendError::Closed(_) => closed(),
+            })?,
+        }
+        outcome.await.map_err(|_| closed())?
+    }
+}
+
+fn closed() -> AppError {
+    AppError::new(StatusCode::SERVICE_UNAVAILABLE, "message writer stopped")
+}
+
+// Writes queued messages for as long as the server runs. Once a message arrives the writer waits up to
+// INGEST_BATCH_WINDOW_MS for more, or until it has INGEST_BATCH_SIZE, and stores them all in one transaction
+pub async fn run(state: Arc<AppState>, mut rx: mpsc::Receiver<Job>) {
+    let batch_size = state.config.ingest_batch_size;
+    let batch_window = state.config.ingest_batch_window;
+
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        // with no window, only what's already queued joins the batch
+        let deadline = Instant::now() + batch_window;
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(job)) => batch.push(job),
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let (prepared, replies): (Vec<PreparedMessage>, Vec<_>) = batch
+            .into_iter()
+            .map(|job| (job.prepared, job.reply))
+            .unzip();
+        let outcomes = messages::insert_batch(&state, prepared).await;
+        for (reply, outcome) in replies.into_iter().zip(outcomes) {
+            // the socket may have closed while its message waited, it's stored all the same
+            let _ = reply.send(outcome);
+        }
+    }
+}
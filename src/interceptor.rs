@@ -0,0 +1,83 @@
+use axum::async_trait;
+
+use crate::{error::AppError, msg};
+
+// Extension point run on every message after validation and before it's stored, over REST or the
+// WebSocket, so deployments can filter, enrich or score messages. Dry runs go through it too
+#[async_trait]
+pub trait MessageInterceptor: Send + Sync {
+    // may rewrite `msg` in place, or return an error to reject it
+    async fn before_insert(&self, msg: &mut msg::Message) -> Result<(), AppError>;
+}
+
+// The default, lets every message through untouched
+pub struct NoopInterceptor;
+
+#[async_trait]
+impl MessageInterceptor for NoopInterceptor {
+    async fn before_insert(&self, _msg: &mut msg::Message) -> Result<(), AppError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{messages, state::AppState};
+
+    // turns away messages mentioning spam and shouts the rest
+    struct Shouting;
+
+    #[async_trait]
+    impl MessageInterceptor for Shouting {
+        async fn before_insert(&self, msg: &mut msg::Message) -> Result<(), AppError> {
+            if msg.text.contains("spam") {
+                return Err(AppError::new(StatusCode::FORBIDDEN, "no spam"));
+            }
+            msg.text = msg.text.to_uppercase();
+            Ok(())
+        }
+    }
+
+    fn chat(text: &str) -> msg::CreateMessage {
+        msg::CreateMessage {
+            time: msg::now_millis(),
+            user_id: String::from("ann"),
+            username: String::from("ann"),
+            text: text.to_string(),
+            channel: String::from("general"),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn installed_interceptor_rejects_and_rewrites_messages() {
+        let state = Arc::into_inner(crate::state::test_state(|_| {})).unwrap();
+        let state: Arc<AppState> = Arc::new(state.with_interceptor(Arc::new(Shouting)));
+
+        let Err(err) = messages::insert_message(&state, chat("buy spam"), false).await else {
+            panic!("the interceptor let spam through");
+        };
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+
+        let (sent, _) = messages::insert_message(&state, chat("hello"), false)
+            .await
+            .unwrap();
+        assert_eq!(sent.text, "HELLO");
+        let id = sent.id.clone();
+        let stored: Vec<String> = state
+            .call_db("stored", move |conn| {
+                conn.prepare("SELECT text FROM messages WHERE id = ?")
+                    .unwrap()
+                    .query_map([id], |row| row.get(0))
+                    .unwrap()
+                    .collect::<Result<_, _>>()
+                    .unwrap()
+            })
+            .await;
+        assert_eq!(stored, vec!["HELLO"]);
+    }
+}
@@ -1,48 +1,251 @@
-use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    response::IntoResponse,
-};
-use axum::{
-    extract::State,
-    http::StatusCode,
-    routing::{any, get, post},
-    Error, Json, Router,
-};
-use axum_extra::{headers, TypedHeader};
+use axum::{routing::get, Router};
 use dotenv::dotenv;
-use futures::{SinkExt, StreamExt};
+use rusqlite::{ErrorCode, OpenFlags};
 use rusqlite_migration::{Migrations, M};
-use serde::{Deserialize, Serialize};
 use std::{
     env,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+use tower_http::{
+    request_id::{PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
 };
-use tokio::sync::{broadcast, mpsc};
-use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-//allows to extract the IP of connecting user
-use axum::extract::connect_info::ConnectInfo;
-
+use config::{Config, MigrateMode};
+use interceptor::NoopInterceptor;
+use state::AppState;
+
+mod admin;
+mod archive;
+mod auth;
+mod body;
+mod bots;
+mod channels;
+mod client_ip;
+mod compress;
+mod config;
+mod connections;
+mod cors;
+mod crypto;
+mod deliveries;
+mod delta;
+mod error;
+mod expiry;
+mod filter;
+mod health;
+mod ingest;
+mod interceptor;
+mod messages;
 mod msg;
-
-async fn migrate(db_path: &String) {
-    let mut conn = rusqlite::Connection::open(db_path).unwrap();
-
-    // 1️⃣ Define migrations
-    let migrations = Migrations::new(vec![
+mod recent;
+mod reports;
+mod request_id;
+mod resume;
+mod selftest;
+mod state;
+mod stats;
+mod users;
+mod ws;
+
+// 1️⃣ Define migrations, `MIGRATE_MODE=validate` expects the db to be at the last one
+fn migrations() -> Vec<M<'static>> {
+    vec![
         M::up("CREATE TABLE users(id TEXT PRIMARY KEY, username TEXT NOT NULL UNIQUE);"),
         M::up("CREATE TABLE messages(id TEXT PRIMARY KEY, time INTEGER NOT NULL, user_id TEXT NOT NULL, username TEXT NOT NULL, text TEXT NOT NULL, reply_to TEXT);"),
         M::up("ALTER TABLE messages ADD COLUMN channel TEXT NOT NULL DEFAULT 'main';"),
-    ]);
+        M::up("CREATE TABLE channels(name TEXT PRIMARY KEY, max_messages INTEGER);"),
+        M::up("ALTER TABLE messages ADD COLUMN text_nonce BLOB;"),
+        M::up("CREATE TABLE reports(id TEXT PRIMARY KEY, message_id TEXT NOT NULL, reporter_id TEXT NOT NULL, reason TEXT NOT NULL, created_at INTEGER NOT NULL, UNIQUE(message_id, reporter_id));"),
+        // backfill the depth of existing replies by walking each chain down from its root
+        M::up("ALTER TABLE messages ADD COLUMN thread_depth INTEGER NOT NULL DEFAULT 0;
+               WITH RECURSIVE depths(id, depth) AS (
+                   SELECT id, 0 FROM messages WHERE reply_to IS NULL OR reply_to NOT IN (SELECT id FROM messages)
+                   UNION ALL
+                   SELECT m.id, d.depth + 1 FROM messages m JOIN depths d ON m.reply_to = d.id
+               )
+               UPDATE messages SET thread_depth = COALESCE((SELECT depth FROM depths WHERE depths.id = messages.id), 0);"),
+        M::up("CREATE INDEX messages_user_id_time ON messages(user_id, time);"),
+        M::up("ALTER TABLE channels ADD COLUMN slow_mode_secs INTEGER;"),
+        // channel names are now normalized on write, fold existing ones the same way
+        M::up("UPDATE messages SET channel = lower(trim(channel));
+               UPDATE OR IGNORE channels SET name = lower(trim(name));"),
+        // backs the `time DESC, id DESC` message order, uuidv7 ids sort by creation time within a `time`
+        M::up("CREATE INDEX messages_time_id ON messages(time, id);"),
+        M::up("CREATE INDEX messages_channel_user_id ON messages(channel, user_id, time);"),
+        // the newest message each user has acknowledged receiving per channel
+        M::up("CREATE TABLE deliveries (
+                   user_id TEXT NOT NULL,
+                   channel TEXT NOT NULL,
+                   message_id TEXT NOT NULL,
+                   time INTEGER NOT NULL,
+                   PRIMARY KEY (user_id, channel)
+               );"),
+        // set when `text` holds zstd compressed bytes, before any encryption
+        M::up("ALTER TABLE messages ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0;"),
+        // a banned user can't post until `banned_until`, or ever when it's NULL
+        M::up("ALTER TABLE users ADD COLUMN banned INTEGER NOT NULL DEFAULT 0;
+               ALTER TABLE users ADD COLUMN banned_until INTEGER;"),
+        // number existing messages per channel in `time, id` order, new ones take `channel_seq.next`
+        M::up("ALTER TABLE messages ADD COLUMN seq INTEGER;
+               UPDATE messages SET seq = numbered.seq FROM (
+                   SELECT id, ROW_NUMBER() OVER (PARTITION BY channel ORDER BY time, id) seq FROM messages
+               ) numbered WHERE numbered.id = messages.id;
+               CREATE TABLE channel_seq(channel TEXT PRIMARY KEY, next INTEGER NOT NULL);
+               INSERT INTO channel_seq SELECT channel, MAX(seq) + 1 FROM messages GROUP BY channel;
+               CREATE UNIQUE INDEX messages_channel_seq ON messages(channel, seq);"),
+        // also serves the per-user `time DESC, id DESC` order, not just the rate limit's time range
+        M::up("CREATE INDEX messages_user_id_time_id ON messages(user_id, time, id);
+               DROP INDEX messages_user_id_time;"),
+        // channels implied by existing messages get a row, created when their first message was sent
+        M::up("ALTER TABLE channels ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+               INSERT OR IGNORE INTO channels (name) SELECT DISTINCT channel FROM messages;
+               UPDATE channels SET created_at = COALESCE(
+                   (SELECT MIN(time) FROM messages WHERE channel = channels.name),
+                   CAST(strftime('%s', 'now') AS INTEGER) * 1000
+               );"),
+        // finds each channel's newest message for the previews without scanning the channel
+        M::up("CREATE INDEX messages_channel_time_id ON messages(channel, time, id);"),
+        // disappearing messages, deleted by the expiry sweep once `expires_at` passes
+        M::up("ALTER TABLE messages ADD COLUMN expires_at INTEGER;
+               CREATE INDEX messages_expires_at ON messages(expires_at) WHERE expires_at IS NOT NULL;"),
+        // `delivered` is a JSON object of the last seq a closed socket was sent per channel
+        M::up("CREATE TABLE resume_tokens (
+                   token TEXT PRIMARY KEY,
+                   delivered TEXT NOT NULL,
+                   expires_at INTEGER NOT NULL
+               );
+               CREATE INDEX resume_tokens_expires_at ON resume_tokens(expires_at);"),
+        M::up("ALTER TABLE messages ADD COLUMN format TEXT NOT NULL DEFAULT 'plain';"),
+        M::up("ALTER TABLE channels ADD COLUMN topic TEXT;"),
+        // who created a channel by posting to it, `NULL` for channels set up by an admin or before this
+        M::up("ALTER TABLE channels ADD COLUMN created_by TEXT;
+               CREATE INDEX channels_created_by ON channels(created_by);"),
+        // the db itself turns away a second message under a key, however the requests race
+        M::up("ALTER TABLE messages ADD COLUMN idempotency_key TEXT;
+               CREATE UNIQUE INDEX messages_user_id_idempotency_key ON messages(user_id, idempotency_key)
+                   WHERE idempotency_key IS NOT NULL;"),
+        // WebSocket sessions, only recorded with LOG_CONNECTIONS
+        M::up("CREATE TABLE connections (
+                   id TEXT PRIMARY KEY,
+                   user_id TEXT,
+                   ip TEXT NOT NULL,
+                   user_agent TEXT NOT NULL,
+                   connected_at INTEGER NOT NULL,
+                   disconnected_at INTEGER
+               );
+               CREATE INDEX connections_connected_at ON connections(connected_at);
+               CREATE INDEX connections_user_id_connected_at ON connections(user_id, connected_at);"),
+        // app-specific JSON attached to a message, stored as sent
+        M::up("ALTER TABLE messages ADD COLUMN metadata TEXT;"),
+        // a snapshot of the message this one quotes, as JSON sealed like `text` when encryption is on
+        M::up("ALTER TABLE messages ADD COLUMN quote TEXT;
+               ALTER TABLE messages ADD COLUMN quote_nonce BLOB;"),
+        // idle channels are archived and read-only until unarchived, which restarts their idle clock
+        M::up("ALTER TABLE channels ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+               ALTER TABLE channels ADD COLUMN unarchived_at INTEGER;"),
+        // integration tokens, each posts as a bot user of its own
+        M::up("CREATE TABLE bots(
+                   token TEXT PRIMARY KEY,
+                   user_id TEXT NOT NULL UNIQUE REFERENCES users(id),
+                   created_at INTEGER NOT NULL
+               );"),
+        // how a user's name is shown, set with `PATCH /users/:id`
+        M::up("ALTER TABLE users ADD COLUMN color TEXT;
+               ALTER TABLE users ADD COLUMN status TEXT;"),
+        // deleted messages for `GET /messages/delta`, the trigger catches every delete whether it's by an admin,
+        // expiry or trimming a channel to its `max_messages`
+        M::up("CREATE TABLE message_tombstones(
+                   id TEXT PRIMARY KEY,
+                   channel TEXT NOT NULL,
+                   deleted_at INTEGER NOT NULL
+               );
+               CREATE INDEX message_tombstones_channel_deleted_at ON message_tombstones(channel, deleted_at);
+               CREATE INDEX message_tombstones_deleted_at ON message_tombstones(deleted_at);
+               CREATE TRIGGER messages_tombstone AFTER DELETE ON messages BEGIN
+                   INSERT OR REPLACE INTO message_tombstones (id, channel, deleted_at)
+                   VALUES (old.id, old.channel, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER));
+               END;"),
+        // when the server stored the message, which `GET /messages/delta` pages on because `time` is the client's
+        M::up("ALTER TABLE messages ADD COLUMN received_at INTEGER;
+               UPDATE messages SET received_at = time;
+               CREATE INDEX messages_channel_received_at ON messages(channel, received_at);
+               CREATE INDEX messages_received_at ON messages(received_at);"),
+        // folding channel names skipped the rows that collided, like `Main` beside `main`. merge each into the
+        // normalized row, or else the oldest, which keeps any setting it lacks. everything else referring to a
+        // channel has been normalized since, so only `channels` has leftovers
+        M::up("UPDATE channels SET
+                   max_messages = COALESCE(max_messages,
+                       (SELECT MIN(c.max_messages) FROM channels c WHERE lower(trim(c.name)) = lower(trim(channels.name)))),
+                   slow_mode_secs = COALESCE(slow_mode_secs,
+                       (SELECT MIN(c.slow_mode_secs) FROM channels c WHERE lower(trim(c.name)) = lower(trim(channels.name)))),
+                   topic = COALESCE(topic,
+                       (SELECT MIN(c.topic) FROM channels c WHERE lower(trim(c.name)) = lower(trim(channels.name))));
+               DELETE FROM channels WHERE rowid NOT IN (
+                   SELECT COALESCE(MIN(CASE WHEN name = lower(trim(name)) THEN rowid END), MIN(rowid))
+                   FROM channels GROUP BY lower(trim(name))
+               );
+               UPDATE channels SET name = lower(trim(name));"),
+    ]
+}
 
-    // Apply some PRAGMA, often better to do it outside of migrations
-    conn.pragma_update_and_check(None, "journal_mode", &"WAL", |_| Ok(()))
-        .unwrap();
+// Explains a db failure at startup, with a hint when another process holds the lock
+fn startup_error(db_path: &str, action: &str, err: &rusqlite::Error) -> String {
+    match err.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => format!(
+            "failed to {action}: database at {db_path} is locked, check for a stale process \
+             still holding it or a leftover {db_path}-wal file"
+        ),
+        _ => format!("failed to {action} at {db_path}: {err}"),
+    }
+}
 
-    // 2️⃣ Update the database schema, atomically
-    migrations.to_latest(&mut conn).unwrap();
+fn migration_error(db_path: &str, action: &str, err: rusqlite_migration::Error) -> String {
+    match &err {
+        rusqlite_migration::Error::RusqliteError { err, .. } => startup_error(db_path, action, err),
+        _ => format!("failed to {action}: {err}"),
+    }
+}
+
+async fn migrate(db_path: &str, mode: MigrateMode) -> Result<(), String> {
+    let migrations = migrations();
+    let latest_version = migrations.len();
+    let migrations = Migrations::new(migrations);
+
+    match mode {
+        // the deployment migrates separately and vouches for the schema
+        MigrateMode::Skip => Ok(()),
+        MigrateMode::Validate => {
+            let conn =
+                rusqlite::Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                    .map_err(|err| startup_error(db_path, "open database", &err))?;
+            let current_version = migrations
+                .current_version(&conn)
+                .map_err(|err| migration_error(db_path, "read schema version", err))?;
+            let current_version = usize::from(&current_version);
+            if current_version != latest_version {
+                return Err(format!(
+                    "database schema is at version {current_version}, expected {latest_version}"
+                ));
+            }
+            Ok(())
+        }
+        MigrateMode::Apply => {
+            let mut conn = rusqlite::Connection::open(db_path)
+                .map_err(|err| startup_error(db_path, "open database", &err))?;
+
+            // Apply some PRAGMA, often better to do it outside of migrations
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+                .map_err(|err| startup_error(db_path, "enable WAL", &err))?;
+
+            // 2️⃣ Update the database schema, atomically
+            migrations
+                .to_latest(&mut conn)
+                .map_err(|err| migration_error(db_path, "migrate database", err))
+        }
+    }
 }
 
 #[tokio::main]
@@ -50,37 +253,96 @@ async fn main() {
     // Load from .env file
     dotenv().ok();
 
-    let db_path = std::env::var("SQLITE_DB_PATH").expect("SQLITE_DB_PATH must be set in env.");
-
-    // Run any new migrations
-    migrate(&db_path).await;
-
-    // Set up db connection
-    let conn = tokio_rusqlite::Connection::open(db_path).await.unwrap();
-
     // initialize tracing
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
                 // format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
-                format!("tower_http=debug").into()
+                format!("{}=info,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
             }),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // `--selftest` checks the db with a round trip through the handlers and exits instead of serving
+    let selftest = env::args().any(|arg| arg == "--selftest");
+
+    let db_path = std::env::var("SQLITE_DB_PATH").expect("SQLITE_DB_PATH must be set in env.");
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+    // settings that contradict each other stop startup before anything is touched
+    let cors = match cors::layer(&config) {
+        Ok(cors) => cors,
+        Err(err) => {
+            tracing::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    // Run any new migrations, or check they've been run
+    if let Err(err) = migrate(&db_path, config.migrate_mode).await {
+        tracing::error!("{err}");
+        std::process::exit(1);
+    }
+
+    // Set up db connection
+    let conn = match tokio_rusqlite::Connection::open(&db_path).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            let err = match err {
+                tokio_rusqlite::Error::Rusqlite(err) => {
+                    startup_error(&db_path, "open database", &err)
+                }
+                err => format!("failed to open database at {db_path}: {err}"),
+            };
+            tracing::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    let (ingest, ingest_rx) = ingest::queue(&config).unzip();
+    // deployments that pre-process messages install their own `MessageInterceptor` here
+    let state =
+        Arc::new(AppState::new(conn, config, ingest).with_interceptor(Arc::new(NoopInterceptor)));
+    if selftest {
+        let passed = selftest::run(state).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    tokio::spawn(expiry::run(state.clone()));
+    if let Some(idle) = state.config.archive_idle {
+        tokio::spawn(archive::run(state.clone(), idle));
+    }
+    if let Some(ingest_rx) = ingest_rx {
+        tokio::spawn(ingest::run(state.clone(), ingest_rx));
+    }
+
     // build our application with a route
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
-        // `POST /users` goes to `create_user`
-        .route("/users", post(create_user))
-        .route("/users", get(get_users))
-        .route("/messages", post(create_message))
-        .route("/messages", get(get_messages))
-        .route("/ws", any(ws_handler))
-        .with_state(Arc::new(AppState::new(conn)))
-        .layer(CorsLayer::permissive());
+        .merge(health::router())
+        .merge(users::router())
+        .merge(bots::router())
+        .merge(messages::router())
+        .merge(delta::router())
+        .merge(channels::router())
+        .merge(config::router())
+        .merge(reports::router())
+        .merge(stats::router())
+        .merge(ws::router())
+        .with_state(state.clone())
+        .layer(cors);
+    // operational endpoints are for scripts and dashboards, not browsers, so they get no CORS headers
+    let internal = admin::router().with_state(state.clone());
+    let (app, internal) = match state.config.internal_port {
+        Some(port) => (app, Some((port, internal))),
+        None => (app.merge(internal), None),
+    };
 
     let port = env::var("PORT")
         .unwrap_or("3000".into())
@@ -89,276 +351,136 @@ async fn main() {
     println!("Attempting to bind to port {port}...");
 
     // run our app with hyper, listening globally on `port`
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
+    let listener = bind(port).await;
+    if let Some((port, internal)) = internal {
+        let listener = bind(port).await;
+        // stops with the process, requests to it don't hold up a shutdown
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                traced(internal).into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+    }
+    let app = traced(app);
     axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(shutdown_signal(state.clone()))
     .await
     .unwrap();
-}
-
-// basic handler that responds with a static string
-async fn root() -> &'static str {
-    "Hello, World!"
-}
-
-async fn create_user(
-    State(state): State<Arc<AppState>>,
-    // this argument tells axum to parse the request body
-    // as JSON into a `CreateUser` type
-    Json(payload): Json<CreateUser>,
-) -> (StatusCode, Json<User>) {
-    // insert your application logic here
-    let user: User = User {
-        id: uuidv7::create(),
-        username: payload.username,
-    };
-
-    let user_copy = user.clone();
-
-    // Add user to users table
-    state
-        .conn
-        .call_unwrap(|conn| {
-            conn.execute(
-                "INSERT INTO users VALUES (?, ?)",
-                [user_copy.id, user_copy.username],
-            )
-            .unwrap();
-        })
-        .await;
-
-    // this will be converted into a JSON response
-    // with a status code of `201 Created`
-    (StatusCode::CREATED, Json(user))
-}
-
-async fn get_users(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Vec<User>>) {
-    let users = state
-        .conn
-        .call_unwrap(|conn| -> Result<Vec<User>, Error> {
-            let mut stmt = conn
-                .prepare("SELECT id, username FROM users LIMIT 100;")
-                .unwrap();
-            let users = stmt
-                .query_map([], |row| {
-                    Ok(User {
-                        id: row.get(0)?,
-                        username: row.get(1)?,
-                    })
-                })
-                .unwrap()
-                .collect::<std::result::Result<Vec<User>, rusqlite::Error>>()
-                .unwrap();
-
-            Ok(users)
-        })
-        .await
-        .unwrap();
-
-    (StatusCode::OK, Json(users))
-}
-
-async fn create_message(
-    State(state): State<Arc<AppState>>,
-    Json(payload): Json<msg::CreateMessage>,
-) -> (StatusCode, Json<msg::Message>) {
-    let msg: msg::Message = msg::Message {
-        id: uuidv7::create(),
-        time: payload.time,
-        user_id: payload.user_id,
-        username: payload.username,
-        text: payload.text,
-        channel: payload.channel,
-        reply_to: payload.reply_to,
-    };
-
-    let msg_copy = msg.clone();
-
-    // Add user to users table
-    state.conn.call_unwrap(move |conn| match msg_copy.reply_to {
-        Some(reply_to) => {
-            conn.execute(
-                "INSERT INTO messages VALUES (?, ?, ?, ?, ?, ?, ?)",
-                [
-                    msg_copy.id,
-                    msg_copy.time.to_string(),
-                    msg_copy.user_id,
-                    msg_copy.username,
-                    msg_copy.text,
-                    reply_to,
-                    msg_copy.channel,
-                ],
-            )
-            .unwrap();
-        }
-        None => {
-            conn.execute(
-                "INSERT INTO messages (id, time, user_id, username, text, channel) VALUES (?, ?, ?, ?, ?, ?)",
-                [
-                    msg_copy.id,
-                    msg_copy.time.to_string(),
-                    msg_copy.user_id,
-                    msg_copy.username,
-                    msg_copy.text,
-                    msg_copy.channel,
-                ],
-            )
-            .unwrap();
-        }
-    })
-    .await;
-
-    // this will be converted into a JSON response
-    // with a status code of `201 Created`
-    (StatusCode::CREATED, Json(msg))
-}
 
-async fn get_messages(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Vec<msg::Message>>) {
-    let messages = state
-        .conn
-        .call_unwrap(|conn| -> Result<Vec<msg::Message>, Error> {
-            let mut stmt = conn
-                .prepare("SELECT * FROM messages ORDER BY time DESC LIMIT 100;")
-                .unwrap();
-            let messages = stmt
-                .query_map([], |row| {
-                    Ok(msg::Message {
-                        id: row.get(0)?,
-                        time: row.get(1)?,
-                        user_id: row.get(2)?,
-                        username: row.get(3)?,
-                        text: row.get(4)?,
-                        channel: row.get(6)?,
-                        reply_to: row.get(5).unwrap_or(None),
-                        // encrypt_meta: row.get(6).unwrap_or(None),
-                        // encrypt_meta_sig: row.get(7).unwrap_or(None),
-                    })
-                })
-                .unwrap()
-                .collect::<std::result::Result<Vec<msg::Message>, rusqlite::Error>>()
-                .unwrap();
-
-            Ok(messages)
-        })
-        .await
-        .unwrap();
-
-    (StatusCode::OK, Json(messages))
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-enum EncryptAlg {
-    X25519,
+    // closed sockets may still be saving their resume tokens, give them a moment before exiting
+    let deadline = Instant::now() + SOCKET_CLOSE_TIMEOUT;
+    while state.connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
 }
 
-// the input to our `create_user` handler
-#[derive(Deserialize)]
-struct CreateUser {
-    username: String,
+async fn bind(port: u16) -> tokio::net::TcpListener {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    tracing::debug!("listening on {}", listener.local_addr().unwrap());
+    listener
 }
 
-// the output to our `create_user` handler
-#[derive(Serialize, Clone)]
-struct User {
-    id: String,
-    username: String,
+// Gives each request an id, traced and echoed on the response
+fn traced(router: Router) -> Router {
+    // layers run outside in, so the id is set before the request is traced and echoed on the response
+    router
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::span))
+        .layer(SetRequestIdLayer::x_request_id(request_id::MakeRequestUuid))
 }
 
-// #[derive(Serialize, Deserialize, Clone)]
-// struct EncryptMeta {
-//     time: u64,
-//     alg: EncryptAlg,
-//     user_id: String,
-//     public_key: String,
-// }
+// how long shutdown waits for closed sockets to finish up once the server has stopped
+const SOCKET_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
-// Reference: https://gist.github.com/hexcowboy/8ebcf13a5d3b681aa6c684ad51dd6e0c
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    user_agent: Option<TypedHeader<headers::UserAgent>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
-        user_agent.to_string()
-    } else {
-        String::from("Unknown browser")
+// Resolves on ctrl-c or SIGTERM once `/ready` has been failing for the drain period,
+// the server then stops accepting connections and waits for open requests and sockets to finish
+async fn shutdown_signal(state: Arc<AppState>) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
     };
-    println!("{user_agent} at {addr} connected.");
-    // finalize the upgrade process by returning upgrade callback.
-    // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_upgrade(socket, addr, state))
-}
-
-async fn handle_upgrade(socket: WebSocket, _addr: SocketAddr, state: Arc<AppState>) {
-    // split the websocket stream into a sender (sink) and receiver (stream)
-    let (mut sink, mut stream) = socket.split();
-    // create an mpsc so we can send messages to the sink from multiple threads
-    let (sender, mut receiver) = mpsc::channel::<String>(16);
-
-    // spawn a task that forwards messages from the mpsc to the sink
-    tokio::spawn(async move {
-        while let Some(message) = receiver.recv().await {
-            if sink.send(message.into()).await.is_err() {
-                break;
-            }
-        }
-    });
-
-    // subscribe to the chat channel
-    let mut rx_chat = state.tx.subscribe();
-
-    // whenever a chat is sent to rx_chat, forward it to the mpsc
-    let send_task_sender = sender.clone();
-    let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx_chat.recv().await {
-            if send_task_sender
-                .send(format!("New message: {}", msg))
-                .await
-                .is_err()
-            {
-                break;
-            }
-        }
-    });
-
-    // clone the tx channel so we can send messages to it
-    let tx_chat = state.tx.clone();
-
-    // whenever a user sends a chat, send it to the tx_chat
-    let recv_task_sender = sender.clone();
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(Message::Text(text))) = stream.next().await {
-            let _ = tx_chat.send(format!("{}", text));
-            if recv_task_sender
-                .send(String::from("Your message has been sent"))
-                .await
-                .is_err()
-            {
-                break;
-            }
-        }
-    });
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
     tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
-    };
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!(
+        drain_secs = state.config.shutdown_drain.as_secs(),
+        "shutting down"
+    );
+    state.shutting_down.store(true, Ordering::Relaxed);
+    tokio::time::sleep(state.config.shutdown_drain).await;
+
+    // open sockets would hold up the shutdown, swapping the broadcast channel closes them
+    // and they save their resume tokens on the way out
+    let capacity = state.broadcast.read().unwrap().capacity;
+    state.resize_broadcast(capacity);
 }
 
-struct AppState {
-    // channel used to send messages to all connected clients
-    tx: broadcast::Sender<String>,
-    conn: tokio_rusqlite::Connection,
+// basic handler that responds with a static string
+async fn root() -> &'static str {
+    "Hello, World!"
 }
 
-impl AppState {
-    fn new(conn: tokio_rusqlite::Connection) -> Self {
-        let (tx, _) = broadcast::channel(16);
-        Self { tx, conn }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folding_channel_names_merges_the_ones_that_collide() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        let migrations = Migrations::new(migrations());
+        // the 9 migrations before channel names were folded
+        migrations.to_version(&mut conn, 9).unwrap();
+        conn.execute_batch(
+            "INSERT INTO channels (name, max_messages, slow_mode_secs) VALUES
+                 ('Main', 100, NULL), ('main', NULL, 5), (' Lobby ', NULL, 10), ('LOBBY', 50, NULL);
+             INSERT INTO messages (id, time, user_id, username, text, channel) VALUES
+                 ('a', 1, 'u', 'u', 'hi', 'Main'), ('b', 2, 'u', 'u', 'hi', ' LOBBY');",
+        )
+        .unwrap();
+        migrations.to_latest(&mut conn).unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT name, max_messages, slow_mode_secs FROM channels ORDER BY name")
+            .unwrap();
+        let channels: Vec<(String, Option<i64>, Option<i64>)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            channels,
+            vec![
+                // the lobby row the fold renamed survives and takes the other's limit
+                ("lobby".to_string(), Some(50), Some(10)),
+                // the normalized main row survives and takes the other's limit
+                ("main".to_string(), Some(100), Some(5)),
+            ]
+        );
+        let channels: Vec<String> = conn
+            .prepare("SELECT channel FROM messages ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(channels, vec!["main", "lobby"]);
     }
 }
@@ -0,0 +1,2173 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Error, Json, Router,
+};
+use rusqlite::{
+    types::{Type, Value},
+    ErrorCode, OptionalExtension,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+use crate::{
+    auth::Admin,
+    body::JsonOrForm,
+    compress,
+    config::{Config, ControlCharPolicy, TimeSkewPolicy},
+    crypto::TextCipher,
+    error::{AppError, ValidationError},
+    filter::{self, FilterMode},
+    msg,
+    recent::RecentMessages,
+    state::AppState,
+    ws,
+};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/messages", post(create_message))
+        .route("/messages", get(get_messages))
+        .route("/messages/batch", post(get_messages_batch))
+        .route("/messages/ids", get(get_message_ids))
+        .route("/messages/delete", post(delete_messages))
+        .route("/messages/:id", delete(delete_message))
+        .route("/messages/:id/context", get(get_message_context))
+}
+
+// most ids a single `POST /messages/batch` or `POST /messages/delete` may list
+const MAX_BATCH_IDS: usize = 100;
+
+// longest reply preview snippet, in characters
+const REPLY_SNIPPET_LEN: usize = 100;
+
+// longest `Idempotency-Key` a client may send, uuids and hashes fit with room to spare
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+#[derive(Deserialize)]
+pub struct CreateMessageQuery {
+    // validate the message without storing or broadcasting it
+    #[serde(default)]
+    dry_run: bool,
+}
+
+pub async fn create_message(
+    State(state): State<Arc<AppState>>,
+    admin: Option<Admin>,
+    Query(query): Query<CreateMessageQuery>,
+    headers: HeaderMap,
+    JsonOrForm(mut payload): JsonOrForm<msg::CreateMessage>,
+) -> Result<Response, AppError> {
+    let mut errors = ValidationError::default();
+    if payload.id.is_some() {
+        errors.add(
+            "id",
+            "can only be set over the WebSocket, to an id it reserved",
+        );
+    }
+    if let Some(key) = headers.get("idempotency-key") {
+        match key.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => {
+                payload.idempotency_key = Some(key.to_string());
+            }
+            _ => errors.add(
+                "Idempotency-Key",
+                format!("must be between 1 and {MAX_IDEMPOTENCY_KEY_LEN} visible ascii characters"),
+            ),
+        }
+    }
+    errors.check()?;
+    if query.dry_run {
+        store_message(&state, payload, admin.is_some(), true).await?;
+        return Ok((StatusCode::OK, Json(json!({ "valid": true }))).into_response());
+    }
+
+    let (msg, _) = insert_message(&state, payload, admin.is_some()).await?;
+
+    // this will be converted into a JSON response
+    // with a status code of `201 Created`
+    Ok((StatusCode::CREATED, Json(msg)).into_response())
+}
+
+// shared by the REST and WebSocket paths: persists the message, then broadcasts it to all sockets,
+// returning how many sockets it was broadcast to. `is_admin` senders bypass channel moderation such as slow mode
+pub async fn insert_message(
+    state: &AppState,
+    payload: msg::CreateMessage,
+    is_admin: bool,
+) -> Result<(msg::Message, usize), AppError> {
+    store_message(state, payload, is_admin, false).await
+}
+
+// Stores a batch from the ingestion queue in one transaction, then broadcasts the new messages in the order
+// they were queued. Each message is checked on its own, so one being turned away doesn't hold back the rest
+pub async fn insert_batch(
+    state: &AppState,
+    batch: Vec<PreparedMessage>,
+) -> Vec<Result<(msg::Message, usize), AppError>> {
+    let limits = InsertLimits::new(&state.config);
+    let cipher = state.cipher.clone();
+    let recent = state.recent.clone();
+    let sender = state.broadcaster();
+
+    let committed = state
+        .call_db("insert_batch", move |conn| {
+            let tx = conn.transaction().unwrap();
+            let inserted: Vec<(msg::Message, Result<Inserted, AppError>)> = batch
+                .into_iter()
+                .map(|prepared| {
+                    let inserted = insert_prepared(&tx, &prepared, limits, cipher.as_ref(), false);
+                    (prepared.msg, inserted)
+                })
+                .collect();
+            tx.commit().unwrap();
+
+            inserted
+                .into_iter()
+                .map(|(msg, inserted)| Ok(publish(msg, inserted?, recent.as_deref(), &sender)))
+                .collect::<Vec<Result<Committed, AppError>>>()
+        })
+        .await;
+
+    committed
+        .into_iter()
+        .map(|committed| opened(state, committed?))
+        .collect()
+}
+
+// The insert's outcome, before a replayed message is opened
+enum Inserted {
+    // `max_messages` is the channel's, for trimming the recent buffer the way the insert trimmed the db
+    New {
+        // the message's id, unless it had to be generated again
+        id: String,
+        seq: u64,
+        max_messages: Option<u32>,
+        quote: Option<Box<msg::Quote>>,
+        // the sender's, for the broadcast frame
+        color: Option<String>,
+    },
+    Replayed(Box<StoredMessage>),
+}
+
+// A committed insert's outcome, once a new message has been published
+enum Committed {
+    // with how many sockets it was broadcast to
+    New(msg::Message, usize),
+    // the message an earlier request stored under the same idempotency key, nothing new was stored
+    Replayed(StoredMessage),
+}
+
+// Gives a newly committed message its seq, adds it to the recent buffer and broadcasts it.
+// Called on the db connection right after the commit, so sockets get each channel's messages in seq order
+// however the tasks that sent them are scheduled afterwards
+fn publish(
+    msg: msg::Message,
+    inserted: Inserted,
+    recent: Option<&RecentMessages>,
+    sender: &broadcast::Sender<ws::ServerFrame>,
+) -> Committed {
+    let (id, seq, max_messages, quote, color) = match inserted {
+        Inserted::New {
+            id,
+            seq,
+            max_messages,
+            quote,
+            color,
+        } => (id, seq, max_messages, quote, color),
+        Inserted::Replayed(existing) => return Committed::Replayed(*existing),
+    };
+    let msg = msg::Message {
+        id,
+        seq,
+        quote,
+        ..msg
+    };
+    if let Some(recent) = recent {
+        // pages are served from the buffer as they'd be read from the db, without the color
+        recent.push(msg.clone());
+        // the new message counts towards what the db trimmed the channel to
+        if let Some(max_messages) = max_messages {
+            recent.truncate(&msg.channel, max_messages as usize);
+        }
+    }
+    let msg = msg::Message { color, ..msg };
+    // no subscribers is not an error, the message is persisted either way
+    let receivers = sender
+        .send(ws::ServerFrame::Message(Box::new(msg.clone())))
+        .unwrap_or(0);
+    Committed::New(msg, receivers)
+}
+
+// The message with how many sockets it was broadcast to, a replayed one is opened and wasn't broadcast again
+fn opened(state: &AppState, committed: Committed) -> Result<(msg::Message, usize), AppError> {
+    match committed {
+        Committed::New(msg, receivers) => Ok((msg, receivers)),
+        // it was broadcast when it was first stored
+        Committed::Replayed(existing) => Ok((existing.open(state)?, 0)),
+    }
+}
+
+// The message the user stored under `idempotency_key`, if any
+fn find_idempotent(
+    conn: &rusqlite::Connection,
+    user_id: &str,
+    idempotency_key: &str,
+) -> Option<StoredMessage> {
+    conn.query_row(
+        &format!(
+            "SELECT {MESSAGE_COLUMNS} FROM messages WHERE user_id = ? AND idempotency_key = ?"
+        ),
+        [user_id, idempotency_key],
+        StoredMessage::from_row,
+    )
+    .optional()
+    .unwrap()
+}
+
+// A message that passed validation and the interceptor, with its text sealed for storage
+pub struct PreparedMessage {
+    // plaintext, as broadcast and returned
+    msg: msg::Message,
+    metadata: Option<String>,
+    stored_text: Value,
+    text_nonce: Option<Vec<u8>>,
+    compressed: bool,
+    idempotency_key: Option<String>,
+    quote_of: Option<String>,
+    is_admin: bool,
+    // the server made up the id, rather than the client sending one it reserved
+    generated_id: bool,
+    // MAX_MESSAGES_PER_MINUTE unless the sender has a limit of its own
+    max_messages_per_minute: Option<u32>,
+}
+
+impl PreparedMessage {
+    // the id it will be stored under, unless a generated one turns out to be taken
+    pub fn id(&self) -> &str {
+        &self.msg.id
+    }
+
+    pub fn with_max_messages_per_minute(self, max_messages_per_minute: Option<u32>) -> Self {
+        Self {
+            max_messages_per_minute,
+            ..self
+        }
+    }
+}
+
+// The settings the insert checks read, copied out of the config so they can move into a db call
+#[derive(Clone, Copy)]
+struct InsertLimits {
+    auto_create_channels: bool,
+    max_channels_per_user: Option<u32>,
+    duplicate_window: Option<Duration>,
+    max_reply_depth: Option<u32>,
+}
+
+impl InsertLimits {
+    fn new(config: &Config) -> Self {
+        Self {
+            auto_create_channels: config.auto_create_channels,
+            max_channels_per_user: config.max_channels_per_user,
+            duplicate_window: config.duplicate_window,
+            max_reply_depth: config.max_reply_depth,
+        }
+    }
+}
+
+// Runs every check a message must pass, then stores and broadcasts it, unless `dry_run` stops it short of the insert
+async fn store_message(
+    state: &AppState,
+    payload: msg::CreateMessage,
+    is_admin: bool,
+    dry_run: bool,
+) -> Result<(msg::Message, usize), AppError> {
+    let prepared = prepare_message(state, payload, is_admin).await?;
+    store_prepared(state, prepared, dry_run).await
+}
+
+// `store_message` for a message that's been through `prepare_message` already
+pub async fn store_prepared(
+    state: &AppState,
+    prepared: PreparedMessage,
+    dry_run: bool,
+) -> Result<(msg::Message, usize), AppError> {
+    let limits = InsertLimits::new(&state.config);
+    let cipher = state.cipher.clone();
+    let recent = state.recent.clone();
+    let sender = state.broadcaster();
+
+    let committed = state
+        .call_db("insert_message", move |conn| {
+            let tx = conn.transaction().unwrap();
+            let inserted = insert_prepared(&tx, &prepared, limits, cipher.as_ref(), dry_run)?;
+            tx.commit().unwrap();
+
+            // a dry run stored nothing to tell anyone about
+            if dry_run {
+                return Ok(Committed::New(prepared.msg, 0));
+            }
+            Ok(publish(prepared.msg, inserted, recent.as_deref(), &sender))
+        })
+        .await?;
+
+    opened(state, committed)
+}
+
+// The checks that need no db: validation, the word filter and the interceptor, then seals the text
+pub async fn prepare_message(
+    state: &AppState,
+    mut payload: msg::CreateMessage,
+    is_admin: bool,
+) -> Result<PreparedMessage, AppError> {
+    let mut errors = ValidationError::default();
+    let mut channel = msg::normalize_channel(&payload.channel);
+    if channel.is_empty() {
+        if state.config.reject_empty_channel {
+            errors.add("channel", "must not be empty");
+        } else {
+            channel = state.config.default_channel.clone();
+        }
+    }
+    if payload.user_id.is_empty() {
+        errors.add("user_id", "must not be empty");
+    }
+    if payload.username.is_empty() {
+        errors.add("username", "must not be empty");
+    }
+    if payload
+        .ttl_secs
+        .is_some_and(|ttl| ttl == 0 || ttl > state.config.max_ttl_secs)
+    {
+        errors.add(
+            "ttl_secs",
+            format!("must be between 1 and {}", state.config.max_ttl_secs),
+        );
+    }
+    // the db stores times as signed 64-bit integers
+    if i64::try_from(payload.time).is_err() {
+        errors.add("time", "is out of range");
+    } else if let Some(max_skew) = state.config.max_time_skew {
+        // a client's clock that's far off would otherwise put its message out of place in the history
+        let now = msg::now_millis();
+        if payload.time.abs_diff(now) > max_skew.as_millis() as u64 {
+            match state.config.time_skew {
+                TimeSkewPolicy::Clamp => payload.time = now,
+                TimeSkewPolicy::Reject => errors.add(
+                    "time",
+                    format!(
+                        "must be within {} seconds of the server's clock",
+                        max_skew.as_secs()
+                    ),
+                ),
+            }
+        }
+    }
+    // before the length check, text that was nothing but control characters is empty
+    let (stripped, had_control_chars) = filter::strip_control_chars(&payload.text);
+    match state.config.control_chars {
+        ControlCharPolicy::Reject if had_control_chars => {
+            errors.add("text", "must not contain control characters")
+        }
+        ControlCharPolicy::Reject => {}
+        ControlCharPolicy::Strip => payload.text = stripped,
+    }
+    let text_len = payload.text.chars().count();
+    if text_len == 0 || text_len > state.config.max_message_len {
+        errors.add(
+            "text",
+            format!(
+                "must be between 1 and {} characters",
+                state.config.max_message_len
+            ),
+        );
+    }
+    if let Some(filter) = &state.word_filter {
+        let (masked, matched) = filter.mask(&payload.text);
+        match filter.mode {
+            FilterMode::Reject if matched => errors.add("text", "contains a blocked word"),
+            FilterMode::Reject => {}
+            FilterMode::Mask => payload.text = masked,
+        }
+    }
+    // kept serialized for the insert, the limit is on what's stored
+    let metadata = payload
+        .metadata
+        .as_ref()
+        .map(|metadata| metadata.to_string());
+    if metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.len() > state.config.max_metadata_len)
+    {
+        errors.add(
+            "metadata",
+            format!(
+                "must be at most {} bytes as JSON",
+                state.config.max_metadata_len
+            ),
+        );
+    }
+    errors.check()?;
+
+    let idempotency_key = payload.idempotency_key.take();
+    let generated_id = payload.id.is_none();
+    let mut msg: msg::Message = msg::Message {
+        id: payload.id.unwrap_or_else(uuidv7::create),
+        time: payload.time,
+        // claimed in the insert transaction
+        seq: 0,
+        time_iso: None,
+        user_id: payload.user_id,
+        username: payload.username,
+        // joined from the sender's user by the insert
+        color: None,
+        text: payload.text,
+        channel,
+        reply_to: payload.reply_to,
+        // filled in from the quoted message by the insert
+        quote: None,
+        // counted from when the server got the message, the client's `time` may be off
+        expires_at: payload
+            .ttl_secs
+            .map(|ttl| msg::now_millis().saturating_add(ttl.saturating_mul(1000))),
+        format: payload.format,
+        metadata: payload.metadata,
+        read: None,
+        reply_preview: None,
+    };
+
+    state.interceptor.before_insert(&mut msg).await?;
+
+    // the stored text may be compressed and sealed, the broadcast and response stay plaintext
+    let (stored_text, text_nonce, compressed) = seal_text(state, &msg.text);
+
+    Ok(PreparedMessage {
+        msg,
+        metadata,
+        stored_text,
+        text_nonce,
+        compressed,
+        idempotency_key,
+        quote_of: payload.quote_of,
+        is_admin,
+        generated_id,
+        max_messages_per_minute: state.config.max_messages_per_minute,
+    })
+}
+
+// Stores the message within the caller's transaction, trimming the channel to its `max_messages`.
+// It gets a savepoint of its own, so a message that's turned away leaves nothing behind
+// and the transaction can go on to the next one
+fn insert_prepared(
+    conn: &rusqlite::Connection,
+    prepared: &PreparedMessage,
+    limits: InsertLimits,
+    cipher: Option<&TextCipher>,
+    dry_run: bool,
+) -> Result<Inserted, AppError> {
+    // a retry comes back with what the first attempt stored, before any check can turn it away
+    if let Some(key) = &prepared.idempotency_key {
+        if let Some(existing) = find_idempotent(conn, &prepared.msg.user_id, key) {
+            return Ok(Inserted::Replayed(Box::new(existing)));
+        }
+    }
+
+    conn.execute_batch("SAVEPOINT insert_message").unwrap();
+    let inserted = check_and_insert(conn, prepared, limits, cipher, dry_run);
+    if !matches!(inserted, Ok(Inserted::New { .. })) {
+        conn.execute_batch("ROLLBACK TO insert_message").unwrap();
+    }
+    conn.execute_batch("RELEASE insert_message").unwrap();
+    inserted
+}
+
+fn check_and_insert(
+    conn: &rusqlite::Connection,
+    prepared: &PreparedMessage,
+    limits: InsertLimits,
+    cipher: Option<&TextCipher>,
+    dry_run: bool,
+) -> Result<Inserted, AppError> {
+    let msg = &prepared.msg;
+    let is_admin = prepared.is_admin;
+    let now = msg::now_millis();
+    let window_start = now.saturating_sub(60_000);
+
+    // senders without a user row can still post, they're never banned and have no color
+    let (banned, color): (bool, Option<String>) = conn
+        .query_row(
+            "SELECT banned AND (banned_until IS NULL OR banned_until > ?), color FROM users WHERE id = ?",
+            rusqlite::params![now, msg.user_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .unwrap()
+        .unwrap_or_default();
+    if banned {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "banned"));
+    }
+
+    let channel: Option<(Option<u32>, Option<u64>, bool)> = conn
+        .query_row(
+            "SELECT max_messages, slow_mode_secs, archived FROM channels WHERE name = ?",
+            [&msg.channel],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+        .unwrap();
+    if channel.is_none() && !limits.auto_create_channels {
+        return Err(AppError::new(StatusCode::BAD_REQUEST, "channel not found"));
+    }
+    let channel_exists = channel.is_some();
+    let (max_messages, slow_mode_secs, archived) = channel.unwrap_or_default();
+    if archived {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "channel is archived"));
+    }
+
+    // posting to a new channel creates it, which counts against the user's cap
+    if let Some(max) = limits
+        .max_channels_per_user
+        .filter(|_| !channel_exists && !is_admin)
+    {
+        let created: u32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM channels WHERE created_by = ?",
+                [&msg.user_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        if created >= max {
+            return Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                format!("a user may create at most {max} channels"),
+            ));
+        }
+    }
+
+    // an accidental double send, the same text as the user's last message in the channel
+    if let Some(window) = limits.duplicate_window {
+        let duplicate_since = now.saturating_sub(window.as_millis() as u64);
+        let last: Option<(String, Value, Option<Vec<u8>>, bool)> = conn
+            .query_row(
+                "SELECT id, text, text_nonce, compressed FROM messages WHERE user_id = ? AND channel = ? AND time > ?
+                 ORDER BY time DESC, id DESC LIMIT 1",
+                rusqlite::params![msg.user_id, msg.channel, duplicate_since],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()
+            .unwrap();
+        if let Some((id, text, text_nonce, compressed)) = last {
+            let text = open_text(cipher, &id, text, text_nonce, compressed)?;
+            if text == msg.text {
+                return Err(AppError::new(StatusCode::CONFLICT, "duplicate"));
+            }
+        }
+    }
+
+    if let Some(slow_mode_secs) = slow_mode_secs.filter(|_| !is_admin) {
+        let last_time: Option<u64> = conn
+            .query_row(
+                "SELECT MAX(time) FROM messages WHERE user_id = ? AND channel = ?",
+                [&msg.user_id, &msg.channel],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let next_allowed = last_time.map(|last| last + slow_mode_secs * 1000);
+        if let Some(next_allowed) = next_allowed.filter(|&next| next > now) {
+            return Err(AppError::new(StatusCode::TOO_MANY_REQUESTS, "slow mode")
+                .with_retry_after(Duration::from_millis(next_allowed - now)));
+        }
+    }
+
+    // counted from the db rather than memory so the limit survives restarts and holds across instances
+    if let Some(max) = prepared.max_messages_per_minute {
+        let recent: u32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM messages WHERE user_id = ? AND time > ?",
+                rusqlite::params![msg.user_id, window_start],
+                |row| row.get(0),
+            )
+            .unwrap();
+        if recent >= max {
+            // a slot frees up once enough of the oldest messages leave the window
+            let freeing_time: u64 = conn
+                .query_row(
+                    "SELECT time FROM messages WHERE user_id = ? AND time > ? ORDER BY time LIMIT 1 OFFSET ?",
+                    rusqlite::params![msg.user_id, window_start, recent - max],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            let retry_after = (freeing_time + 60_000).saturating_sub(now);
+            return Err(AppError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many messages, slow down",
+            )
+            .with_retry_after(Duration::from_millis(retry_after)));
+        }
+    }
+
+    // replies sit one level below their parent, whose depth is cached on its row
+    let thread_depth = match &msg.reply_to {
+        Some(reply_to) => {
+            let parent_depth: u32 = conn
+                .query_row(
+                    "SELECT thread_depth FROM messages WHERE id = ?",
+                    [reply_to],
+                    |row| row.get(0),
+                )
+                .optional()
+                .unwrap()
+                .ok_or_else(|| {
+                    AppError::new(StatusCode::BAD_REQUEST, "reply_to message not found")
+                })?;
+            parent_depth + 1
+        }
+        None => 0,
+    };
+    if limits.max_reply_depth.is_some_and(|max| thread_depth > max) {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "reply would exceed the maximum thread depth",
+        ));
+    }
+    // the quoted message as it reads now, later changes to it don't reach the copy
+    let quote = match &prepared.quote_of {
+        Some(quote_of) => {
+            let quoted = conn
+                .query_row(
+                    &format!(
+                        "SELECT {MESSAGE_COLUMNS} FROM messages WHERE id = ? AND (expires_at IS NULL OR expires_at > ?)"
+                    ),
+                    rusqlite::params![quote_of, now],
+                    StoredMessage::from_row,
+                )
+                .optional()
+                .unwrap()
+                .ok_or_else(|| {
+                    AppError::new(StatusCode::BAD_REQUEST, "quote_of message not found")
+                })?
+                .open_with(cipher)?;
+            Some(Box::new(msg::Quote {
+                id: quoted.id,
+                user_id: quoted.user_id,
+                username: quoted.username,
+                text: quoted.text,
+                time: quoted.time,
+            }))
+        }
+        None => None,
+    };
+    // dry runs don't claim a sequence number
+    if dry_run {
+        return Ok(Inserted::New {
+            id: msg.id.clone(),
+            seq: 0,
+            max_messages: None,
+            quote,
+            color,
+        });
+    }
+    let (stored_quote, quote_nonce) = seal_quote(cipher, quote.as_deref());
+
+    if !channel_exists {
+        conn.execute(
+            "INSERT INTO channels (name, created_at, created_by) VALUES (?, ?, ?)",
+            rusqlite::params![msg.channel, msg.time, msg.user_id],
+        )
+        .map_err(|err| {
+            tracing::error!("failed to create channel {}: {err}", msg.channel);
+            AppError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to create channel",
+            )
+        })?;
+    }
+
+    // the write lock taken here is held until commit, so concurrent inserts can't share a seq
+    let seq: u64 = conn
+        .query_row(
+            "INSERT INTO channel_seq (channel, next) VALUES (?, 2)
+             ON CONFLICT(channel) DO UPDATE SET next = next + 1 RETURNING next - 1",
+            [&msg.channel],
+            |row| row.get(0),
+        )
+        .unwrap();
+
+    // a generated id that's somehow taken gets one more try with a new one, a reserved id is the client's to fix
+    let mut id = msg.id.clone();
+    let mut regenerated = false;
+    while let Err(err) = conn.execute(
+        "INSERT INTO messages (id, time, user_id, username, text, reply_to, channel, text_nonce, thread_depth, compressed, seq, expires_at, format, idempotency_key, metadata, quote, quote_nonce, received_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        rusqlite::params![
+            id,
+            msg.time,
+            msg.user_id,
+            msg.username,
+            prepared.stored_text,
+            msg.reply_to,
+            msg.channel,
+            prepared.text_nonce,
+            thread_depth,
+            prepared.compressed,
+            seq,
+            msg.expires_at,
+            msg.format,
+            prepared.idempotency_key,
+            prepared.metadata,
+            stored_quote,
+            quote_nonce,
+            now,
+        ],
+    ) {
+        // another request under the same key was stored since the lookup above, answer with its message.
+        // Holding the write lock, this sees it, and the savepoint rolls back the channel and seq claimed here
+        let conflict = err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation);
+        let existing = prepared
+            .idempotency_key
+            .as_deref()
+            .filter(|_| conflict)
+            .and_then(|key| find_idempotent(conn, &msg.user_id, key));
+        if let Some(existing) = existing {
+            return Ok(Inserted::Replayed(Box::new(existing)));
+        }
+        let id_taken = conflict
+            && conn
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM messages WHERE id = ?)",
+                    [&id],
+                    |row| row.get(0),
+                )
+                .unwrap();
+        if !id_taken {
+            tracing::error!("failed to insert message: {err}");
+            return Err(AppError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to store message",
+            ));
+        }
+        if !prepared.generated_id || regenerated {
+            return Err(AppError::new(StatusCode::CONFLICT, "id collision"));
+        }
+        id = uuidv7::create();
+        regenerated = true;
+    }
+
+    if let Some(max_messages) = max_messages {
+        conn.execute(
+            "DELETE FROM messages WHERE id IN (SELECT id FROM messages WHERE channel = ?1 ORDER BY time DESC, id DESC LIMIT -1 OFFSET ?2)",
+            rusqlite::params![msg.channel, max_messages],
+        )
+        .unwrap();
+    }
+
+    Ok(Inserted::New {
+        id,
+        seq,
+        max_messages,
+        quote,
+        color,
+    })
+}
+
+// Removes a message for good and tells connected sockets, so they can drop it too
+async fn delete_message(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if remove_messages(&state, vec![id]).await.is_empty() {
+        return Err(AppError::new(StatusCode::NOT_FOUND, "message not found"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct DeleteMessages {
+    ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DeletedMessages {
+    deleted: usize,
+    // requested ids with no message, already deleted or never sent
+    not_found: Vec<String>,
+}
+
+// `delete_message` for up to MAX_BATCH_IDS messages at once, all removed in one transaction
+async fn delete_messages(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<DeleteMessages>,
+) -> Result<(StatusCode, Json<DeletedMessages>), AppError> {
+    if payload.ids.len() > MAX_BATCH_IDS {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_BATCH_IDS} messages can be deleted at once"),
+        ));
+    }
+
+    let deleted = remove_messages(&state, payload.ids.clone())
+        .await
+        .into_iter()
+        .collect::<HashSet<String>>();
+    let not_found = payload
+        .ids
+        .into_iter()
+        .filter(|id| !deleted.contains(id))
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect();
+
+    Ok((
+        StatusCode::OK,
+        Json(DeletedMessages {
+            deleted: deleted.len(),
+            not_found,
+        }),
+    ))
+}
+
+// Deletes the messages in one transaction and broadcasts a `deleted` event for each, returning the ids that existed
+async fn remove_messages(state: &AppState, ids: Vec<String>) -> Vec<String> {
+    if ids.is_empty() {
+        return vec![];
+    }
+
+    let recent = state.recent.clone();
+    let deleted = state
+        .call_db("delete_messages", move |conn| {
+            // one bound `?` per id, the ids themselves never reach the SQL text
+            let placeholders = vec!["?"; ids.len()].join(", ");
+            // the channel goes out with the event, so clients can find the message to drop
+            let deleted = conn
+                .prepare(&format!(
+                    "DELETE FROM messages WHERE id IN ({placeholders}) RETURNING id, channel;"
+                ))
+                .unwrap()
+                .query_map(rusqlite::params_from_iter(&ids), |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .unwrap()
+                .collect::<std::result::Result<Vec<(String, String)>, rusqlite::Error>>()
+                .unwrap();
+            if let Some(recent) = &recent {
+                for (id, channel) in &deleted {
+                    recent.remove(channel, id);
+                }
+            }
+            deleted
+        })
+        .await;
+
+    deleted
+        .into_iter()
+        .map(|(id, channel)| {
+            state.broadcast(ws::ServerFrame::Deleted {
+                id: id.clone(),
+                channel,
+            });
+            id
+        })
+        .collect()
+}
+
+// Columns selected by every message query, in the order `StoredMessage::from_row` reads them
+pub const MESSAGE_COLUMNS: &str =
+    "id, time, user_id, username, text, reply_to, channel, text_nonce, compressed, seq, expires_at, format, metadata, quote, quote_nonce";
+
+// a stored message with whether the `for_user` reader has acked it, when one was asked about
+type ReadMessage = (StoredMessage, Option<bool>);
+// a page of messages, the total the query matches and whether there are more after it
+type Page = (Vec<msg::Message>, u64, bool);
+
+// A message as read from the database, its text may still be compressed or encrypted
+pub struct StoredMessage {
+    // `text` is left empty until `open` fills it in from the stored columns
+    message: msg::Message,
+    text: Value,
+    text_nonce: Option<Vec<u8>>,
+    compressed: bool,
+    quote: Option<String>,
+    quote_nonce: Option<Vec<u8>>,
+}
+
+impl StoredMessage {
+    pub fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            message: msg::Message {
+                id: row.get(0)?,
+                time: row.get(1)?,
+                seq: row.get(9)?,
+                time_iso: None,
+                user_id: row.get(2)?,
+                username: row.get(3)?,
+                color: None,
+                text: String::new(),
+                channel: row.get(6)?,
+                reply_to: row.get(5).unwrap_or(None),
+                expires_at: row.get(10)?,
+                format: row.get(11)?,
+                metadata: row
+                    .get::<_, Option<String>>(12)?
+                    .map(|metadata| serde_json::from_str(&metadata))
+                    .transpose()
+                    .map_err(|err| {
+                        rusqlite::Error::FromSqlConversionFailure(12, Type::Text, Box::new(err))
+                    })?,
+                // opened along with the text
+                quote: None,
+                read: None,
+                reply_preview: None,
+                // encrypt_meta: row.get(6).unwrap_or(None),
+                // encrypt_meta_sig: row.get(7).unwrap_or(None),
+            },
+            text: row.get(4)?,
+            text_nonce: row.get(7)?,
+            compressed: row.get(8)?,
+            quote: row.get(13)?,
+            quote_nonce: row.get(14)?,
+        })
+    }
+
+    // Decrypts and decompresses the text if it was stored that way
+    pub fn open(self, state: &AppState) -> Result<msg::Message, AppError> {
+        self.open_with(state.cipher.as_ref())
+    }
+
+    // `open` for inside a db call, where only the cipher is at hand
+    pub fn open_with(self, cipher: Option<&TextCipher>) -> Result<msg::Message, AppError> {
+        let text = open_text(
+            cipher,
+            &self.message.id,
+            self.text,
+            self.text_nonce,
+            self.compressed,
+        )?;
+        let quote = match self.quote {
+            Some(quote) => Some(open_quote(
+                cipher,
+                &self.message.id,
+                quote,
+                self.quote_nonce,
+            )?),
+            None => None,
+        };
+        Ok(msg::Message {
+            text,
+            quote,
+            ..self.message
+        })
+    }
+}
+
+// Prepares `text` for the `text`, `text_nonce` and `compressed` columns. Text over COMPRESS_TEXT_OVER
+// is compressed first, as ciphertext doesn't compress, then encrypted when encryption at rest is enabled.
+// Compressed plaintext is stored as a BLOB, everything else as TEXT
+pub fn seal_text(state: &AppState, text: &str) -> (Value, Option<Vec<u8>>, bool) {
+    // short text isn't worth it, zstd's frame overhead can outweigh what it saves
+    let compressed = state
+        .config
+        .compress_text_over
+        .filter(|&over| text.len() > over)
+        .and_then(|_| compress::compress(text.as_bytes()));
+    let is_compressed = compressed.is_some();
+
+    match (&state.cipher, compressed) {
+        (Some(cipher), compressed) => {
+            let plaintext = compressed.as_deref().unwrap_or(text.as_bytes());
+            let (ciphertext, nonce) = cipher.encrypt(plaintext);
+            (Value::Text(ciphertext), Some(nonce), is_compressed)
+        }
+        (None, Some(compressed)) => (Value::Blob(compressed), None, true),
+        (None, None) => (Value::Text(text.to_string()), None, false),
+    }
+}
+
+// Prepares a quote snapshot for the `quote` and `quote_nonce` columns, JSON encrypted like `text` when
+// encryption at rest is enabled. Quotes aren't compressed, the text they copy is already length-limited
+fn seal_quote(
+    cipher: Option<&TextCipher>,
+    quote: Option<&msg::Quote>,
+) -> (Option<String>, Option<Vec<u8>>) {
+    let Some(quote) = quote else {
+        return (None, None);
+    };
+    let json = serde_json::to_string(quote).unwrap();
+    match cipher {
+        Some(cipher) => {
+            let (ciphertext, nonce) = cipher.encrypt(json.as_bytes());
+            (Some(ciphertext), Some(nonce))
+        }
+        None => (Some(json), None),
+    }
+}
+
+// Reverses `seal_quote`, `id` is the quoting message's and only used for logging
+fn open_quote(
+    cipher: Option<&TextCipher>,
+    id: &str,
+    quote: String,
+    quote_nonce: Option<Vec<u8>>,
+) -> Result<Box<msg::Quote>, AppError> {
+    let json = open_text(cipher, id, Value::Text(quote), quote_nonce, false)?;
+    serde_json::from_str(&json).map_err(|_| unreadable_text(id, "decoded"))
+}
+
+// Reverses `seal_text` on the stored columns with the state's cipher, `id` is only used for logging
+pub fn open_text(
+    cipher: Option<&TextCipher>,
+    id: &str,
+    text: Value,
+    text_nonce: Option<Vec<u8>>,
+    compressed: bool,
+) -> Result<String, AppError> {
+    let plaintext = match (text, text_nonce) {
+        (Value::Text(text), None) if !compressed => return Ok(text),
+        (Value::Text(ciphertext), Some(nonce)) => cipher
+            .and_then(|cipher| cipher.decrypt(&ciphertext, &nonce))
+            .ok_or_else(|| unreadable_text(id, "decrypted"))?,
+        (Value::Blob(bytes), None) => bytes,
+        _ => return Err(unreadable_text(id, "read")),
+    };
+    let plaintext = if compressed {
+        compress::decompress(&plaintext).ok_or_else(|| unreadable_text(id, "decompressed"))?
+    } else {
+        plaintext
+    };
+
+    String::from_utf8(plaintext).map_err(|_| unreadable_text(id, "decoded"))
+}
+
+// A stored text that can't be turned back into plaintext means a wrong key or a corrupt row
+fn unreadable_text(id: &str, step: &str) -> AppError {
+    tracing::error!("message {id} could not be {step}");
+    AppError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("message could not be {step}"),
+    )
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TimeFormat {
+    Iso8601,
+}
+
+// Sort order of `GET /messages`, newest first either way
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum MessageOrder {
+    // client supplied `time`, then id
+    #[default]
+    Time,
+    // the channel's sequence number, only meaningful within one channel
+    Seq,
+}
+
+impl MessageOrder {
+    fn sql(self) -> &'static str {
+        match self {
+            MessageOrder::Time => "time DESC, id DESC",
+            MessageOrder::Seq => "seq DESC",
+        }
+    }
+}
+
+// Position in the message order, serialized as `<time>:<id>`, or `<seq>` in seq order
+enum Cursor {
+    Time { time: u64, id: String },
+    Seq(u64),
+}
+
+impl Cursor {
+    fn parse(cursor: &str, order: MessageOrder) -> Option<Self> {
+        match order {
+            MessageOrder::Time => {
+                let (time, id) = cursor.split_once(':')?;
+                Some(Cursor::Time {
+                    time: time.parse().ok()?,
+                    id: id.to_string(),
+                })
+            }
+            MessageOrder::Seq => Some(Cursor::Seq(cursor.parse().ok()?)),
+        }
+    }
+
+    fn after(msg: &msg::Message, order: MessageOrder) -> String {
+        match order {
+            MessageOrder::Time => format!("{}:{}", msg.time, msg.id),
+            MessageOrder::Seq => msg.seq.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct GetMessagesQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time_format: Option<TimeFormat>,
+    // only return messages in this channel, required by `order=seq`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channel: Option<String>,
+    // only return messages by this user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    // only return messages with `from <= time < to`, needs a channel or user_id to narrow the scan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<u64>,
+    // mark each message `read` or not from this user's delivery acks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    for_user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    order: Option<MessageOrder>,
+    // only return messages older than this cursor, taken from `X-Next-Cursor`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    before: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limit: Option<u32>,
+    // wrap the messages in a `MessagePage` instead of returning a bare array
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    envelope: bool,
+    // give replies a `reply_preview` of the message they answer
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    expand_replies: bool,
+}
+
+// A page of messages with its paging details inline, for `?envelope=true`
+#[derive(Serialize)]
+struct MessagePage {
+    messages: Vec<msg::Message>,
+    // the `before` for the next page, `None` on the last one
+    next_cursor: Option<String>,
+    has_more: bool,
+}
+
+// Newest messages first, with `X-Total-Count`, `X-Next-Cursor` and a `Link: rel="next"` header
+// when there are older messages left to page through
+pub async fn get_messages(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<GetMessagesQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let limit = state.config.pagination.limit(query.limit)?;
+    let order = query.order.unwrap_or_default();
+    let channel = query.channel.as_deref().map(msg::normalize_channel);
+    if order == MessageOrder::Seq && channel.is_none() {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "order=seq requires a channel, sequence numbers are per channel",
+        ));
+    }
+    let has_time_range = query.from.is_some() || query.to.is_some();
+    if has_time_range && channel.is_none() && query.user_id.is_none() {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "from and to require a channel or user_id",
+        ));
+    }
+    let user_id = query.user_id.clone();
+    let for_user = query.for_user.clone();
+    let (from, to) = (query.from, query.to);
+    let before = match &query.before {
+        Some(before) => Some(
+            Cursor::parse(before, order)
+                .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "invalid cursor"))?,
+        ),
+        None => None,
+    };
+
+    // a plain first page of a channel can be served from its buffer of recent messages
+    let recent = state.recent.clone().filter(|recent| {
+        order == MessageOrder::Time
+            && channel.is_some()
+            && user_id.is_none()
+            && !has_time_range
+            && before.is_none()
+            && for_user.is_none()
+            && limit as usize <= recent.capacity()
+    });
+    let cipher = state.cipher.clone();
+    // the same page asked for in different words gets the same tag
+    let normalized_query = serde_urlencoded::to_string(GetMessagesQuery {
+        channel: channel.clone(),
+        order: Some(order),
+        limit: Some(limit),
+        ..query.clone()
+    })
+    .unwrap();
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let now = msg::now_millis();
+    // the page is `None` when the client's copy, going by its `If-None-Match`, is still current
+    let (etag, page) = state
+        .call_db(
+            "get_messages",
+            move |conn| -> Result<(String, Option<Page>), AppError> {
+                let (mut filters, mut params) =
+                    message_filters(now, channel.as_deref(), user_id, from, to);
+
+                let (total, latest): (u64, Option<String>) = conn
+                    .query_row(
+                        &format!("SELECT COUNT(*), MAX(id) FROM messages{}", where_clause(&filters)),
+                        rusqlite::params_from_iter(&params),
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .unwrap();
+
+                // every insert moves `channel_seq.next` and every delete leaves a tombstone, expiring changes
+                // the count. Tombstones are pruned, so their newest time goes in along with how many there are
+                let (scope_filter, scope_params) = match &channel {
+                    Some(channel) => (" WHERE channel = ?1", vec![Value::Text(channel.clone())]),
+                    None => ("", vec![]),
+                };
+                let changes: (Option<u64>, u64, Option<u64>) = conn
+                    .query_row(
+                        &format!(
+                            "SELECT (SELECT SUM(next) FROM channel_seq{scope_filter}),
+                                    (SELECT COUNT(*) FROM message_tombstones{scope_filter}),
+                                    (SELECT MAX(deleted_at) FROM message_tombstones{scope_filter})"
+                        ),
+                        rusqlite::params_from_iter(&scope_params),
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )
+                    .unwrap();
+
+                // acks only move forward, so their times only add up to more
+                let acks = match &for_user {
+                    Some(for_user) => conn
+                        .query_row(
+                            "SELECT COUNT(*), COALESCE(SUM(time), 0) FROM deliveries WHERE user_id = ?",
+                            [for_user],
+                            |row| Ok(format!("-{}-{}", row.get::<_, u64>(0)?, row.get::<_, u64>(1)?)),
+                        )
+                        .unwrap(),
+                    None => String::new(),
+                };
+                let mut hasher = DefaultHasher::new();
+                (normalized_query, total, latest, changes, acks).hash(&mut hasher);
+                let etag = format!("W/\"{:016x}\"", hasher.finish());
+                if if_none_match.is_some_and(|tags| etag_matches(&tags, &etag)) {
+                    return Ok((etag, None));
+                }
+
+                match before {
+                    Some(Cursor::Time { time, id }) => {
+                        filters.push("(time, id) < (?, ?)");
+                        params.push(Value::Integer(time as i64));
+                        params.push(Value::Text(id));
+                    }
+                    Some(Cursor::Seq(seq)) => {
+                        filters.push("seq < ?");
+                        params.push(Value::Integer(seq as i64));
+                    }
+                    None => {}
+                }
+                // only the count has to come from the db when the channel is buffered
+                let recent_channel = recent.as_ref().zip(channel.as_deref());
+                if let Some((recent, channel)) = recent_channel {
+                    if let Some(messages) = recent.get(channel, limit as usize, now) {
+                        let has_more = total > messages.len() as u64;
+                        return Ok((etag, Some((messages, total, has_more))));
+                    }
+                }
+
+                // one extra row tells us whether there is a next page, a channel that isn't
+                // buffered yet reads enough to fill its buffer
+                let fetch = match recent_channel {
+                    Some((recent, _)) => recent.capacity() + 1,
+                    None => limit as usize + 1,
+                };
+                params.push(Value::Integer(fetch as i64));
+
+                // only joined in when asked for, a message is read once the user acked it or a later one
+                let with_read = for_user.is_some();
+                let (read_column, mut read_params) = match for_user {
+                    Some(for_user) => (
+                        ", EXISTS (
+                             SELECT 1 FROM deliveries d
+                             WHERE d.user_id = ? AND d.channel = messages.channel
+                             AND (messages.time, messages.id) <= (d.time, d.message_id)
+                         )",
+                        vec![Value::Text(for_user)],
+                    ),
+                    None => ("", vec![]),
+                };
+                // the read column's parameter comes first in the statement
+                read_params.extend(params);
+
+                let messages = conn
+                    .prepare(&format!(
+                        "SELECT {MESSAGE_COLUMNS}{read_column} FROM messages{} ORDER BY {} LIMIT ?;",
+                        where_clause(&filters),
+                        order.sql(),
+                    ))
+                    .unwrap()
+                    .query_map(rusqlite::params_from_iter(&read_params), |row| {
+                        let read = if with_read { Some(row.get(15)?) } else { None };
+                        Ok((StoredMessage::from_row(row)?, read))
+                    })
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<ReadMessage>, rusqlite::Error>>()
+                    .unwrap();
+                let mut messages = messages
+                    .into_iter()
+                    .map(|(stored, read)| {
+                        Ok(msg::Message {
+                            read,
+                            ..stored.open_with(cipher.as_ref())?
+                        })
+                    })
+                    .collect::<Result<Vec<msg::Message>, AppError>>()?;
+
+                if let Some((recent, channel)) = recent_channel {
+                    recent.load(channel, messages.clone(), now);
+                }
+
+                let has_more = messages.len() > limit as usize;
+                messages.truncate(limit as usize);
+                Ok((etag, Some((messages, total, has_more))))
+            },
+        )
+        .await?;
+    // the tag is a valid header value, it's made of hex digits
+    let etag = HeaderValue::from_str(&etag).unwrap();
+    let Some((mut messages, total, has_more)) = page else {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    };
+
+    if query.expand_replies {
+        expand_replies(&state, &mut messages).await?;
+    }
+
+    if query.time_format == Some(TimeFormat::Iso8601) {
+        for msg in &mut messages {
+            msg.time_iso = msg::iso8601(msg.time);
+        }
+    }
+
+    let envelope = query.envelope;
+    let next_cursor = messages
+        .last()
+        .filter(|_| has_more)
+        .map(|last| Cursor::after(last, order));
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-total-count", HeaderValue::from(total));
+    headers.insert(header::ETAG, etag);
+    if let Some(cursor) = &next_cursor {
+        // the same query with the cursor moved, urlencoded so any channel name is a valid header value
+        let next = serde_urlencoded::to_string(GetMessagesQuery {
+            before: Some(cursor.clone()),
+            limit: Some(limit),
+            ..query
+        })
+        .unwrap();
+        // the cursor is digits, hex and separators, so it's always a valid header value
+        headers.insert("x-next-cursor", HeaderValue::from_str(cursor).unwrap());
+        headers.insert(
+            header::LINK,
+            HeaderValue::from_str(&format!("</messages?{next}>; rel=\"next\"")).unwrap(),
+        );
+    }
+
+    if envelope {
+        let page = MessagePage {
+            messages,
+            next_cursor,
+            has_more,
+        };
+        return Ok((StatusCode::OK, headers, Json(page)).into_response());
+    }
+    Ok((StatusCode::OK, headers, Json(messages)).into_response())
+}
+
+// Whether an `If-None-Match` header lists `etag` or is `*`. Compared weakly, the tags describe what the
+// messages are rather than the exact bytes of the response
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+// Fills in the replies' `reply_preview` from their parents, read in one query
+async fn expand_replies(state: &AppState, messages: &mut [msg::Message]) -> Result<(), AppError> {
+    let parent_ids = messages
+        .iter()
+        .filter_map(|msg| msg.reply_to.clone())
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect::<Vec<String>>();
+    if parent_ids.is_empty() {
+        return Ok(());
+    }
+
+    let now = msg::now_millis();
+    let parents = state
+        .call_db("expand_replies", move |conn| {
+            let placeholders = vec!["?"; parent_ids.len()].join(", ");
+            let mut params: Vec<Value> = parent_ids.into_iter().map(Value::Text).collect();
+            params.push(Value::Integer(now as i64));
+            conn.prepare(&format!(
+                "SELECT {MESSAGE_COLUMNS} FROM messages
+                 WHERE id IN ({placeholders}) AND (expires_at IS NULL OR expires_at > ?);"
+            ))
+            .unwrap()
+            .query_map(rusqlite::params_from_iter(&params), StoredMessage::from_row)
+            .unwrap()
+            .collect::<std::result::Result<Vec<StoredMessage>, rusqlite::Error>>()
+            .unwrap()
+        })
+        .await
+        .into_iter()
+        .map(|stored| {
+            let parent = stored.open(state)?;
+            let mut text_snippet: String = parent.text.chars().take(REPLY_SNIPPET_LEN).collect();
+            if text_snippet.len() < parent.text.len() {
+                text_snippet.push('…');
+            }
+            let preview = Box::new(msg::ReplyPreview {
+                id: parent.id.clone(),
+                username: parent.username,
+                text_snippet,
+            });
+            Ok((parent.id, preview))
+        })
+        .collect::<Result<HashMap<String, Box<msg::ReplyPreview>>, AppError>>()?;
+
+    for msg in messages {
+        msg.reply_preview = msg
+            .reply_to
+            .as_ref()
+            .and_then(|reply_to| parents.get(reply_to).cloned());
+    }
+    Ok(())
+}
+
+// The `WHERE` conditions for unexpired messages in the channel, by the user and with `from <= time < to`,
+// each only when given, with their params
+pub fn message_filters(
+    now: u64,
+    channel: Option<&str>,
+    user_id: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> (Vec<&'static str>, Vec<Value>) {
+    // expired messages may outlive their `expires_at` until the next sweep
+    let mut filters = vec!["(expires_at IS NULL OR expires_at > ?)"];
+    let mut params: Vec<Value> = vec![Value::Integer(now as i64)];
+    if let Some(channel) = channel {
+        filters.push("channel = ?");
+        params.push(Value::Text(channel.to_string()));
+    }
+    if let Some(user_id) = user_id {
+        filters.push("user_id = ?");
+        params.push(Value::Text(user_id));
+    }
+    if let Some(from) = from {
+        filters.push("time >= ?");
+        params.push(Value::Integer(from as i64));
+    }
+    if let Some(to) = to {
+        filters.push("time < ?");
+        params.push(Value::Integer(to as i64));
+    }
+    (filters, params)
+}
+
+// ` WHERE a AND b` for the given conditions, or nothing when there are none
+pub fn where_clause(filters: &[&str]) -> String {
+    if filters.is_empty() {
+        return String::new();
+    }
+    format!(" WHERE {}", filters.join(" AND "))
+}
+
+#[derive(Deserialize)]
+struct IdsQuery {
+    channel: String,
+    // only messages after this time, in milliseconds since the Unix epoch
+    #[serde(default)]
+    since: Option<u64>,
+    // and, for those at exactly `since`, after this id. The last entry of a full page gives both
+    #[serde(default)]
+    after: Option<String>,
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+// just enough of a message to tell whether a client has it
+#[derive(Serialize)]
+struct MessageId {
+    id: String,
+    time: u64,
+}
+
+// The ids of the channel's messages after `since`, oldest first, for clients that cache message bodies
+// to spot the ones they're missing and fetch them with `POST /messages/batch`
+async fn get_message_ids(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<IdsQuery>,
+) -> Result<(StatusCode, Json<Vec<MessageId>>), AppError> {
+    let limit = state.config.pagination.limit(query.limit)?;
+    let channel = msg::normalize_channel(&query.channel);
+    let now = msg::now_millis();
+
+    let ids = state
+        .call_db("get_message_ids", move |conn| {
+            let (mut filters, mut params) = message_filters(now, Some(&channel), None, None, None);
+            match (query.since, query.after) {
+                (Some(since), Some(after)) => {
+                    filters.push("(time > ? OR (time = ? AND id > ?))");
+                    params.push(Value::Integer(since as i64));
+                    params.push(Value::Integer(since as i64));
+                    params.push(Value::Text(after));
+                }
+                (Some(since), None) => {
+                    filters.push("time > ?");
+                    params.push(Value::Integer(since as i64));
+                }
+                (None, _) => {}
+            }
+            params.push(Value::Integer(limit.into()));
+            conn.prepare(&format!(
+                "SELECT id, time FROM messages{} ORDER BY time, id LIMIT ?;",
+                where_clause(&filters)
+            ))
+            .unwrap()
+            .query_map(rusqlite::params_from_iter(&params), |row| {
+                Ok(MessageId {
+                    id: row.get(0)?,
+                    time: row.get(1)?,
+                })
+            })
+            .unwrap()
+            .collect::<std::result::Result<Vec<MessageId>, rusqlite::Error>>()
+            .unwrap()
+        })
+        .await;
+
+    Ok((StatusCode::OK, Json(ids)))
+}
+
+#[derive(Deserialize)]
+struct BatchQuery {
+    ids: Vec<String>,
+}
+
+// Returns the requested messages in the order they were asked for, skipping unknown ids
+async fn get_messages_batch(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<BatchQuery>,
+) -> Result<(StatusCode, Json<Vec<msg::Message>>), AppError> {
+    if payload.ids.len() > MAX_BATCH_IDS {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_BATCH_IDS} ids can be requested at once"),
+        ));
+    }
+    if payload.ids.is_empty() {
+        return Ok((StatusCode::OK, Json(vec![])));
+    }
+
+    let (filters, mut params) = message_filters(msg::now_millis(), None, None, None, None);
+    params.extend(payload.ids.iter().cloned().map(Value::Text));
+    let id_count = payload.ids.len();
+    let mut found = state
+        .call_db(
+            "get_messages_batch",
+            move |conn| -> Result<Vec<StoredMessage>, Error> {
+                // one bound `?` per id, the ids themselves never reach the SQL text
+                let placeholders = vec!["?"; id_count].join(", ");
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT {MESSAGE_COLUMNS} FROM messages{} AND id IN ({placeholders});",
+                        where_clause(&filters)
+                    ))
+                    .unwrap();
+                let messages = stmt
+                    .query_map(rusqlite::params_from_iter(&params), StoredMessage::from_row)
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<StoredMessage>, rusqlite::Error>>()
+                    .unwrap();
+
+                Ok(messages)
+            },
+        )
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|stored| {
+            let msg = stored.open(&state)?;
+            Ok((msg.id.clone(), msg))
+        })
+        .collect::<Result<HashMap<String, msg::Message>, AppError>>()?;
+
+    // removing from `found` also drops repeated ids after their first occurrence
+    let messages = payload
+        .ids
+        .iter()
+        .filter_map(|id| found.remove(id))
+        .collect();
+
+    Ok((StatusCode::OK, Json(messages)))
+}
+
+// how many messages either side of the target a context view gets unless it asks otherwise
+const DEFAULT_CONTEXT: u32 = 25;
+
+#[derive(Deserialize)]
+struct ContextQuery {
+    // older messages to include
+    #[serde(default)]
+    before: Option<u32>,
+    // newer messages to include
+    #[serde(default)]
+    after: Option<u32>,
+}
+
+// The message with up to `before` older and `after` newer messages from its channel around it, newest first,
+// for jumping to a message from a link
+async fn get_message_context(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<ContextQuery>,
+) -> Result<(StatusCode, Json<Vec<msg::Message>>), AppError> {
+    let max_limit = state.config.pagination.max_limit;
+    let before = query.before.unwrap_or(DEFAULT_CONTEXT).min(max_limit);
+    let after = query.after.unwrap_or(DEFAULT_CONTEXT).min(max_limit);
+
+    let now = msg::now_millis();
+    let messages = state
+        .call_db(
+            "get_message_context",
+            move |conn| -> Option<Vec<StoredMessage>> {
+                let (channel, time): (String, u64) = conn
+                    .query_row(
+                        "SELECT channel, time FROM messages WHERE id = ? AND (expires_at IS NULL OR expires_at > ?)",
+                        rusqlite::params![id, now],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()
+                    .unwrap()?;
+
+                // each side reads outward from the target along the channel's `time, id` index
+                let read_side = |comparison: &str, order: &str, limit: u32| {
+                    conn.prepare(&format!(
+                        "SELECT {MESSAGE_COLUMNS} FROM messages
+                         WHERE channel = ? AND (time, id) {comparison} (?, ?) AND (expires_at IS NULL OR expires_at > ?)
+                         ORDER BY time {order}, id {order} LIMIT ?;"
+                    ))
+                    .unwrap()
+                    .query_map(
+                        rusqlite::params![channel, time, id, now, limit],
+                        StoredMessage::from_row,
+                    )
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<StoredMessage>, rusqlite::Error>>()
+                    .unwrap()
+                };
+                let mut messages = read_side(">=", "ASC", after + 1);
+                messages.reverse();
+                messages.extend(read_side("<", "DESC", before));
+
+                Some(messages)
+            },
+        )
+        .await
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "message not found"))?
+        .into_iter()
+        .map(|stored| stored.open(&state))
+        .collect::<Result<Vec<msg::Message>, AppError>>()?;
+
+    Ok((StatusCode::OK, Json(messages)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+
+    fn chat(user_id: &str, text: &str) -> msg::CreateMessage {
+        msg::CreateMessage {
+            time: msg::now_millis(),
+            user_id: user_id.to_string(),
+            username: user_id.to_string(),
+            text: text.to_string(),
+            channel: String::from("general"),
+            ..Default::default()
+        }
+    }
+
+    async fn count_messages(state: &AppState) -> u64 {
+        state
+            .call_db("count_messages", |conn| {
+                conn.query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+                    .unwrap()
+            })
+            .await
+    }
+
+    // the channel's messages through `GET /messages`, newest first
+    async fn read_channel(state: &Arc<AppState>, query: &str) -> Vec<serde_json::Value> {
+        let uri: axum::http::Uri = format!("/messages?{query}").parse().unwrap();
+        let response = get_messages(
+            State(state.clone()),
+            Query::try_from_uri(&uri).unwrap(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    // the `text`, `text_nonce` and `compressed` columns as stored
+    async fn stored_text(state: &AppState, id: String) -> (Value, Option<Vec<u8>>, bool) {
+        state
+            .call_db("stored_text", move |conn| {
+                conn.query_row(
+                    "SELECT text, text_nonce, compressed FROM messages WHERE id = ?",
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .unwrap()
+            })
+            .await
+    }
+
+    // stands in for a message stored under `id` by someone else
+    async fn insert_row(state: &AppState, id: String) {
+        state
+            .call_db("insert_row", move |conn| {
+                conn.execute(
+                    "INSERT INTO messages (id, time, user_id, username, text, channel) VALUES (?, 1, 'other', 'other', 'taken', 'general')",
+                    [id],
+                )
+                .unwrap();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn reserved_id_that_is_taken_is_a_conflict() {
+        let state = test_state(|_| {});
+        let id = uuidv7::create();
+        insert_row(&state, id.clone()).await;
+
+        let payload = msg::CreateMessage {
+            id: Some(id),
+            ..chat("ann", "hello")
+        };
+        let Err(err) = insert_message(&state, payload, false).await else {
+            panic!("a message under a taken id was stored");
+        };
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        assert_eq!(err.message, "id collision");
+        assert_eq!(count_messages(&state).await, 1);
+    }
+
+    #[tokio::test]
+    async fn generated_id_that_is_taken_is_generated_again() {
+        let state = test_state(|_| {});
+        let prepared = prepare_message(&state, chat("ann", "hello"), false)
+            .await
+            .unwrap();
+        let taken = prepared.msg.id.clone();
+        insert_row(&state, taken.clone()).await;
+
+        let (msg, _) = store_prepared(&state, prepared, false).await.unwrap();
+        assert_ne!(msg.id, taken);
+        assert_eq!(count_messages(&state).await, 2);
+    }
+
+    #[tokio::test]
+    async fn encrypted_text_round_trips() {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        let state = test_state(|config| {
+            config.message_encryption_key = Some(STANDARD.encode([7u8; 32]));
+            // so reads go through the db, not the in-memory copy
+            config.recent_messages = None;
+        });
+
+        let (sent, _) = insert_message(&state, chat("ann", "secret text"), false)
+            .await
+            .unwrap();
+        let (text, nonce, _) = stored_text(&state, sent.id.clone()).await;
+        assert_ne!(text, Value::Text(String::from("secret text")));
+        assert_eq!(nonce.map(|nonce| nonce.len()), Some(12));
+
+        let read = read_channel(&state, "channel=general").await;
+        assert_eq!(read[0]["id"], sent.id.as_str());
+        assert_eq!(read[0]["text"], "secret text");
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_stored_as_is_without_a_key() {
+        let state = test_state(|config| {
+            config.message_encryption_key = None;
+            config.recent_messages = None;
+        });
+
+        let (sent, _) = insert_message(&state, chat("ann", "plain text"), false)
+            .await
+            .unwrap();
+        let (text, nonce, _) = stored_text(&state, sent.id.clone()).await;
+        assert_eq!(text, Value::Text(String::from("plain text")));
+        assert_eq!(nonce, None);
+
+        let read = read_channel(&state, "channel=general").await;
+        assert_eq!(read[0]["text"], "plain text");
+    }
+
+    #[tokio::test]
+    async fn messages_sent_at_the_same_time_are_ordered_by_id() {
+        let state = test_state(|config| config.recent_messages = None);
+        let time = msg::now_millis();
+        // stored oldest id last, so the order can't just be the order they were stored in
+        let mut ids: Vec<String> = (0..5).map(|_| uuidv7::create()).collect();
+        for id in ids.iter().rev() {
+            let payload = msg::CreateMessage {
+                id: Some(id.clone()),
+                time,
+                ..chat("ann", id)
+            };
+            insert_message(&state, payload, false).await.unwrap();
+        }
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+
+        for _ in 0..2 {
+            let read: Vec<_> = read_channel(&state, "channel=general")
+                .await
+                .iter()
+                .map(|msg| msg["id"].as_str().unwrap().to_string())
+                .collect();
+            assert_eq!(read, ids);
+        }
+    }
+
+    #[tokio::test]
+    async fn long_text_is_compressed_and_short_text_is_not() {
+        let state = test_state(|config| {
+            config.compress_text_over = Some(64);
+            config.message_encryption_key = None;
+            config.recent_messages = None;
+        });
+        let long = "all work and no play makes jack a dull boy. ".repeat(20);
+
+        let (short_msg, _) = insert_message(&state, chat("ann", "short"), false)
+            .await
+            .unwrap();
+        let (long_msg, _) = insert_message(&state, chat("ann", &long), false)
+            .await
+            .unwrap();
+
+        let (text, _, compressed) = stored_text(&state, short_msg.id.clone()).await;
+        assert_eq!(text, Value::Text(String::from("short")));
+        assert!(!compressed);
+        let (text, _, compressed) = stored_text(&state, long_msg.id.clone()).await;
+        assert!(compressed);
+        assert!(matches!(text, Value::Blob(blob) if blob.len() < long.len()));
+
+        let read = read_channel(&state, "channel=general").await;
+        let text_of = |id: &str| read.iter().find(|msg| msg["id"] == id).unwrap()["text"].clone();
+        assert_eq!(text_of(&long_msg.id), long.as_str());
+        assert_eq!(text_of(&short_msg.id), "short");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_messages_get_distinct_seqs() {
+        let state = test_state(|_| {});
+        let sends = (0..50).map(|n| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let payload = chat(&format!("user{}", n % 5), &format!("message {n}"));
+                insert_message(&state, payload, false).await.unwrap().0.seq
+            })
+        });
+        let mut seqs = futures::future::join_all(sends)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<u64>>();
+
+        seqs.sort_unstable();
+        assert_eq!(seqs, (1..=50).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_retries_under_one_idempotency_key_store_one_message() {
+        let state = test_state(|_| {});
+        let sends = (0..10).map(|_| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let payload = msg::CreateMessage {
+                    idempotency_key: Some(String::from("key-1")),
+                    ..chat("ann", "hello")
+                };
+                insert_message(&state, payload, false).await.unwrap().0
+            })
+        });
+        let sent = futures::future::join_all(sends)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<msg::Message>>();
+
+        assert!(sent.iter().all(|msg| msg.id == sent[0].id));
+        assert_eq!(count_messages(&state).await, 1);
+
+        // a later retry gets the same message back too
+        let payload = msg::CreateMessage {
+            idempotency_key: Some(String::from("key-1")),
+            ..chat("ann", "hello")
+        };
+        let (replayed, _) = insert_message(&state, payload, false).await.unwrap();
+        assert_eq!(replayed.id, sent[0].id);
+        assert_eq!(replayed.text, "hello");
+        assert_eq!(count_messages(&state).await, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_messages_are_broadcast_in_seq_order() {
+        let state = test_state(|_| {});
+        // room for every frame, so none are lost to lag
+        state.resize_broadcast(64);
+        let mut rx = state.subscribe();
+
+        let sends = (0..40).map(|n| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let payload = chat(&format!("user{}", n % 5), &format!("message {n}"));
+                insert_message(&state, payload, false).await.unwrap();
+            })
+        });
+        for sent in futures::future::join_all(sends).await {
+            sent.unwrap();
+        }
+
+        let mut seqs = vec![];
+        while let Ok(frame) = rx.try_recv() {
+            if let ws::ServerFrame::Message(msg) = frame {
+                seqs.push(msg.seq);
+            }
+        }
+        assert_eq!(seqs, (1..=40).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    async fn control_characters_are_stripped_or_rejected() {
+        let state = test_state(|config| config.control_chars = ControlCharPolicy::Strip);
+        let (sent, _) = insert_message(&state, chat("ann", "\u{1b}[2Jhi\u{202E}"), false)
+            .await
+            .unwrap();
+        assert_eq!(sent.text, "[2Jhi");
+
+        let state = test_state(|config| config.control_chars = ControlCharPolicy::Reject);
+        let Err(err) = insert_message(&state, chat("ann", "\u{1b}[2Jhi"), false).await else {
+            panic!("text with control characters was stored");
+        };
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.field_errors[0].field, "text");
+
+        // nothing but control characters is empty once stripped
+        let state = test_state(|config| config.control_chars = ControlCharPolicy::Strip);
+        assert!(insert_message(&state, chat("ann", "\0\u{7}"), false)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_hidden_before_the_sweep() {
+        let state = test_state(|config| config.recent_messages = None);
+        let (kept, _) = insert_message(&state, chat("ann", "kept"), false)
+            .await
+            .unwrap();
+        let (expired, _) = insert_message(&state, chat("ann", "expired"), false)
+            .await
+            .unwrap();
+        let id = expired.id.clone();
+        state
+            .call_db("expire", move |conn| {
+                conn.execute("UPDATE messages SET expires_at = 1 WHERE id = ?", [id])
+                    .unwrap();
+            })
+            .await;
+
+        let read = read_channel(&state, "channel=general").await;
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0]["id"], kept.id.as_str());
+
+        let batch = BatchQuery {
+            ids: vec![kept.id.clone(), expired.id],
+        };
+        let (_, Json(found)) = get_messages_batch(State(state.clone()), Json(batch))
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, kept.id);
+    }
+
+    #[tokio::test]
+    async fn time_out_of_range_is_rejected() {
+        let state = test_state(|config| config.max_time_skew = None);
+        let payload = msg::CreateMessage {
+            time: u64::MAX,
+            channel: String::from("new-channel"),
+            ..chat("ann", "hello")
+        };
+        let Err(err) = insert_message(&state, payload, false).await else {
+            panic!("a message with an out of range time was stored");
+        };
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.message, "time: is out of range");
+
+        // the db is still there for the next message
+        insert_message(&state, chat("ann", "hello"), false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn skewed_time_is_rejected_or_clamped_when_configured() {
+        let skewed = || msg::CreateMessage {
+            time: msg::now_millis() - 10 * 60 * 1000,
+            ..chat("ann", "hello")
+        };
+        let state = test_state(|_| {});
+        let (sent, _) = insert_message(&state, skewed(), false).await.unwrap();
+        assert!(sent.time < msg::now_millis() - 5 * 60 * 1000);
+
+        let state = test_state(|config| {
+            config.max_time_skew = Some(Duration::from_secs(60));
+            config.time_skew = TimeSkewPolicy::Reject;
+        });
+        let Err(err) = insert_message(&state, skewed(), false).await else {
+            panic!("a message with a skewed time was stored");
+        };
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.field_errors[0].field, "time");
+
+        let state = test_state(|config| {
+            config.max_time_skew = Some(Duration::from_secs(60));
+            config.time_skew = TimeSkewPolicy::Clamp;
+        });
+        let before = msg::now_millis();
+        let (sent, _) = insert_message(&state, skewed(), false).await.unwrap();
+        assert!(sent.time >= before);
+    }
+
+    // the status and `ETag` of `GET /messages`
+    async fn fetch_etag(
+        state: &Arc<AppState>,
+        query: &str,
+        if_none_match: Option<&str>,
+    ) -> (StatusCode, String) {
+        let uri: axum::http::Uri = format!("/messages?{query}").parse().unwrap();
+        let mut headers = HeaderMap::new();
+        if let Some(tag) = if_none_match {
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(tag).unwrap());
+        }
+        let response = get_messages(
+            State(state.clone()),
+            Query::try_from_uri(&uri).unwrap(),
+            headers,
+        )
+        .await
+        .unwrap();
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        (response.status(), etag)
+    }
+
+    #[tokio::test]
+    async fn etag_follows_the_query_and_every_change() {
+        let state = test_state(|_| {});
+        let oldest_id = uuidv7::create();
+        let mut sent = vec![];
+        for i in 0..3 {
+            let (msg, _) = insert_message(&state, chat("ann", &format!("message {i}")), false)
+                .await
+                .unwrap();
+            sent.push(msg);
+        }
+
+        let (_, etag) = fetch_etag(&state, "channel=general&limit=2", None).await;
+        let (status, same) = fetch_etag(&state, "limit=2&channel=General", Some(&etag)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(same, etag);
+        for query in [
+            "channel=general&limit=1",
+            "channel=general&limit=2&envelope=true",
+            "channel=general&limit=2&expand_replies=true",
+            "channel=general&limit=2&time_format=iso8601",
+        ] {
+            let (status, other) = fetch_etag(&state, query, Some(&etag)).await;
+            assert_eq!(status, StatusCode::OK, "{query}");
+            assert_ne!(other, etag, "{query}");
+        }
+        let before = format!(
+            "channel=general&limit=2&before={}:{}",
+            sent[2].time, sent[2].id
+        );
+        let (status, _) = fetch_etag(&state, &before, Some(&etag)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // one message swapped for another keeps the count and the newest id
+        let id = sent[1].id.clone();
+        state
+            .call_db("delete", move |conn| {
+                conn.execute("DELETE FROM messages WHERE id = ?", [id])
+                    .unwrap();
+            })
+            .await;
+        insert_row(&state, oldest_id).await;
+        let (status, changed) = fetch_etag(&state, "channel=general&limit=2", Some(&etag)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(changed, etag);
+    }
+
+    #[tokio::test]
+    async fn buffered_pages_keep_to_max_messages() {
+        let state = test_state(|_| {});
+        state
+            .call_db("limit_channel", |conn| {
+                conn.execute(
+                    "INSERT INTO channels (name, max_messages) VALUES ('general', 3)",
+                    [],
+                )
+                .unwrap();
+            })
+            .await;
+        // the first read starts buffering the channel
+        assert!(read_channel(&state, "channel=general").await.is_empty());
+
+        for i in 0..4 {
+            insert_message(&state, chat("ann", &format!("message {i}")), false)
+                .await
+                .unwrap();
+        }
+        assert_eq!(count_messages(&state).await, 3);
+        assert_eq!(read_channel(&state, "channel=general").await.len(), 3);
+    }
+}
@@ -1,7 +1,61 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize, Clone)]
+pub enum EncryptAlg {
+    X25519,
+}
+
+// #[derive(Serialize, Deserialize, Clone)]
+// struct EncryptMeta {
+//     time: u64,
+//     alg: EncryptAlg,
+//     user_id: String,
+//     public_key: String,
+// }
+
+// How clients should render a message's text, the server stores it as declared and never renders it
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+impl MessageFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            MessageFormat::Plain => "plain",
+            MessageFormat::Markdown => "markdown",
+        }
+    }
+}
+
+impl ToSql for MessageFormat {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for MessageFormat {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "plain" => Ok(MessageFormat::Plain),
+            "markdown" => Ok(MessageFormat::Markdown),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
 
 #[derive(Deserialize)]
+#[cfg_attr(test, derive(Default))]
 pub struct CreateMessage {
+    // only accepted over the WebSocket, and only an id reserved on that socket with `reserve_id`
+    #[serde(default)]
+    pub id: Option<String>,
     pub time: u64,
     // TODO: Remove user_id and username, or potentially just validate them against values in JWT later (to extra processing)
     pub user_id: String,
@@ -11,6 +65,20 @@ pub struct CreateMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub reply_to: Option<String>,
+    // quote this message, its text and author are copied into the new message as they are now
+    #[serde(default)]
+    pub quote_of: Option<String>,
+    // the message is deleted this many seconds after it's sent
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub format: MessageFormat,
+    // app-specific JSON such as bot payloads or link previews, the server stores and returns it untouched
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+    // from the `Idempotency-Key` header of `POST /messages`, a retry under the same key gets the first message back
+    #[serde(skip)]
+    pub idempotency_key: Option<String>,
     // #[serde(skip_serializing_if = "Option::is_none")]
     // #[serde(default)]
     // encrypt_meta: Option<EncryptMeta>,
@@ -20,16 +88,40 @@ pub struct CreateMessage {
 }
 
 #[derive(Serialize, Clone)]
+#[cfg_attr(test, derive(Default))]
 pub struct Message {
     pub id: String,
     pub time: u64,
+    // position in the channel, assigned at insert with no gaps or repeats until messages are trimmed
+    pub seq: u64,
+    // only set when requested with `?time_format=iso8601`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_iso: Option<String>,
     pub user_id: String,
     pub username: String,
+    // the sender's color as it was when the message was sent, only on the message's frame and `POST` response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
     pub text: String,
     pub channel: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub reply_to: Option<String>,
+    // unlike `reply_to`, a copy that still renders after the quoted message is deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quote: Option<Box<Quote>>,
+    // when the message disappears, in milliseconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    pub format: MessageFormat,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    // whether the user asked about with `?for_user=` has acked delivery of this message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read: Option<bool>,
+    // only set when requested with `?expand_replies=true` and the parent still exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_preview: Option<Box<ReplyPreview>>,
     // #[serde(skip_serializing_if = "Option::is_none")]
     // #[serde(default)]
     // encrypt_meta: Option<EncryptMeta>,
@@ -37,3 +129,42 @@ pub struct Message {
     // #[serde(default)]
     // encrypt_meta_sig: Option<String>,
 }
+
+// The quoted message as it was when it was quoted
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Quote {
+    pub id: String,
+    pub user_id: String,
+    pub username: String,
+    pub text: String,
+    pub time: u64,
+}
+
+// Enough of the message a reply answers to render the reply inline
+#[derive(Serialize, Clone)]
+pub struct ReplyPreview {
+    pub id: String,
+    pub username: String,
+    // the start of the parent's text, ending in `…` when cut short
+    pub text_snippet: String,
+}
+
+// Current time in milliseconds since the Unix epoch, the unit used for `Message::time`
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// Formats a `Message::time` as an RFC 3339 / ISO-8601 UTC string, `None` if it's out of chrono's range
+pub fn iso8601(time: u64) -> Option<String> {
+    let millis = i64::try_from(time).ok()?;
+    let time = chrono::DateTime::from_timestamp_millis(millis)?;
+    Some(time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+// Channel names are trimmed and lowercased so `Main`, ` main` and `main` are the same channel
+pub fn normalize_channel(channel: &str) -> String {
+    channel.trim().to_lowercase()
+}
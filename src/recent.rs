@@ -0,0 +1,176 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use crate::msg;
+
+// The newest messages of recently read channels, so the first page of a channel's history skips the db.
+// Loads and inserts happen inside the db call that read or wrote the rows, so they can't interleave wrongly
+pub struct RecentMessages {
+    channels: Mutex<HashMap<String, Recent>>,
+    // messages kept per channel
+    capacity: usize,
+    // channels kept, the least recently read is dropped to make room
+    max_channels: usize,
+}
+
+struct Recent {
+    // oldest first, in the `time, id` order pages are read in
+    messages: VecDeque<msg::Message>,
+    // nothing older is left in the db, so a short buffer is still the whole channel
+    complete: bool,
+    last_read: u64,
+}
+
+impl RecentMessages {
+    pub fn new(capacity: usize, max_channels: usize) -> Self {
+        Self {
+            channels: Mutex::new(HashMap::new()),
+            capacity,
+            max_channels,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // The channel's newest `limit` unexpired messages, newest first, or `None` when the buffer can't tell
+    pub fn get(&self, channel: &str, limit: usize, now: u64) -> Option<Vec<msg::Message>> {
+        let mut channels = self.channels.lock().unwrap();
+        let recent = channels.get_mut(channel)?;
+        recent.last_read = now;
+
+        let messages: Vec<msg::Message> = recent
+            .messages
+            .iter()
+            .rev()
+            .filter(|msg| msg.expires_at.is_none_or(|expires_at| expires_at > now))
+            .take(limit)
+            .cloned()
+            .collect();
+        // expired messages still in the buffer leave gaps that only the db can fill
+        if messages.len() < limit && !recent.complete {
+            return None;
+        }
+        Some(messages)
+    }
+
+    // Starts buffering the channel with its newest messages as read from the db, newest first.
+    // Reading one more than `capacity` tells whether that's the whole channel
+    pub fn load(&self, channel: &str, mut messages: Vec<msg::Message>, now: u64) {
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(channel) && channels.len() >= self.max_channels {
+            let least_read = channels
+                .iter()
+                .min_by_key(|(_, recent)| recent.last_read)
+                .map(|(name, _)| name.clone());
+            if let Some(least_read) = least_read {
+                channels.remove(&least_read);
+            }
+        }
+
+        let complete = messages.len() <= self.capacity;
+        messages.truncate(self.capacity);
+        messages.reverse();
+        channels.insert(
+            channel.to_string(),
+            Recent {
+                messages: messages.into(),
+                complete,
+                last_read: now,
+            },
+        );
+    }
+
+    // Adds a newly stored message to its channel's buffer, if the channel is buffered
+    pub fn push(&self, msg: msg::Message) {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(recent) = channels.get_mut(&msg.channel) else {
+            return;
+        };
+        // `time` comes from the client, so a message can land behind newer ones
+        let position = recent
+            .messages
+            .partition_point(|other| (other.time, &other.id) < (msg.time, &msg.id));
+        // older than everything buffered, where the db may hold messages the buffer doesn't, so only a reload
+        // can tell where it goes
+        if position == 0 && !recent.complete {
+            channels.remove(&msg.channel);
+            return;
+        }
+        recent.messages.insert(position, msg);
+        if recent.messages.len() > self.capacity {
+            recent.messages.pop_front();
+            recent.complete = false;
+        }
+    }
+
+    // Drops all but the channel's newest `keep` messages, after the db trimmed the channel to `max_messages`
+    pub fn truncate(&self, channel: &str, keep: usize) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(recent) = channels.get_mut(channel) {
+            while recent.messages.len() > keep {
+                recent.messages.pop_front();
+            }
+        }
+    }
+
+    pub fn remove(&self, channel: &str, id: &str) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(recent) = channels.get_mut(channel) {
+            recent.messages.retain(|msg| msg.id != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, time: u64) -> msg::Message {
+        msg::Message {
+            id: id.to_string(),
+            time,
+            channel: String::from("general"),
+            ..Default::default()
+        }
+    }
+
+    fn ids(messages: Option<Vec<msg::Message>>) -> Option<Vec<String>> {
+        messages.map(|messages| messages.into_iter().map(|msg| msg.id).collect())
+    }
+
+    #[test]
+    fn late_message_is_inserted_in_order() {
+        let recent = RecentMessages::new(10, 10);
+        recent.load("general", vec![message("c", 30), message("a", 10)], 0);
+        recent.push(message("b", 20));
+        assert_eq!(
+            ids(recent.get("general", 3, 0)),
+            Some(vec!["c".into(), "b".into(), "a".into()])
+        );
+    }
+
+    #[test]
+    fn message_older_than_an_incomplete_buffer_drops_it() {
+        let recent = RecentMessages::new(2, 10);
+        // one more than capacity, so older messages are left in the db
+        recent.load(
+            "general",
+            vec![message("d", 40), message("c", 30), message("a", 10)],
+            0,
+        );
+        recent.remove("general", "c");
+        recent.push(message("b", 20));
+        assert_eq!(ids(recent.get("general", 1, 0)), None);
+
+        recent.load("general", vec![message("d", 40)], 0);
+        recent.push(message("e", 50));
+        assert_eq!(
+            ids(recent.get("general", 2, 0)),
+            Some(vec!["e".into(), "d".into()])
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use rusqlite::{types::Value, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    auth::Admin,
+    error::{AppError, ValidationError},
+    messages, msg,
+    state::AppState,
+};
+
+// reasons are free text, but bounded so reports stay reviewable
+const MAX_REASON_LEN: usize = 500;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/messages/:id/report", post(create_report))
+        .route("/reports", get(get_reports))
+}
+
+#[derive(Deserialize)]
+pub struct CreateReport {
+    pub reporter_id: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Report {
+    pub id: String,
+    pub message_id: String,
+    pub reporter_id: String,
+    pub reason: String,
+    pub created_at: u64,
+}
+
+// a report as listed for admins, with the reported message's text
+#[derive(Serialize)]
+pub struct ReportWithMessage {
+    #[serde(flatten)]
+    pub report: Report,
+    pub message_text: String,
+}
+
+// a report with the reported message's stored text, nonce and compression flag, before opening
+type ReportRow = (Report, Value, Option<Vec<u8>>, bool);
+
+async fn create_report(
+    State(state): State<Arc<AppState>>,
+    Path(message_id): Path<String>,
+    Json(payload): Json<CreateReport>,
+) -> Result<(StatusCode, Json<Report>), AppError> {
+    let reason = payload.reason.trim().to_string();
+    let reason_len = reason.chars().count();
+    let mut errors = ValidationError::default();
+    if reason_len == 0 || reason_len > MAX_REASON_LEN {
+        errors.add(
+            "reason",
+            format!("must be between 1 and {MAX_REASON_LEN} characters"),
+        );
+    }
+    errors.check()?;
+
+    let report = Report {
+        id: uuidv7::create(),
+        message_id,
+        reporter_id: payload.reporter_id,
+        reason,
+        created_at: msg::now_millis(),
+    };
+
+    let report_copy = report.clone();
+
+    state
+        .call_db("create_report", move |conn| {
+            let exists = conn
+                .query_row(
+                    "SELECT 1 FROM messages WHERE id = ?",
+                    [&report_copy.message_id],
+                    |_| Ok(()),
+                )
+                .optional()
+                .unwrap()
+                .is_some();
+            if !exists {
+                return Err(AppError::new(StatusCode::NOT_FOUND, "message not found"));
+            }
+
+            // (message_id, reporter_id) is unique, so a second report from the same user is ignored
+            let inserted = conn
+                .execute(
+                    "INSERT INTO reports (id, message_id, reporter_id, reason, created_at) VALUES (?, ?, ?, ?, ?)
+                     ON CONFLICT(message_id, reporter_id) DO NOTHING",
+                    rusqlite::params![
+                        report_copy.id,
+                        report_copy.message_id,
+                        report_copy.reporter_id,
+                        report_copy.reason,
+                        report_copy.created_at,
+                    ],
+                )
+                .unwrap();
+            if inserted == 0 {
+                return Err(AppError::new(
+                    StatusCode::CONFLICT,
+                    "message already reported by this user",
+                ));
+            }
+
+            Ok(())
+        })
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+#[derive(Deserialize)]
+pub struct ReportsQuery {
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+async fn get_reports(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ReportsQuery>,
+) -> Result<(StatusCode, Json<Vec<ReportWithMessage>>), AppError> {
+    let limit = state.config.pagination.limit(query.limit)?;
+    let rows = state
+        .call_db("get_reports", move |conn| -> Result<Vec<ReportRow>, rusqlite::Error> {
+            let mut stmt = conn.prepare(
+                "SELECT r.id, r.message_id, r.reporter_id, r.reason, r.created_at, m.text, m.text_nonce, m.compressed
+                 FROM reports r JOIN messages m ON m.id = r.message_id
+                 ORDER BY r.created_at DESC, r.id DESC LIMIT ?;",
+            )?;
+            let rows = stmt
+                .query_map([limit], |row| {
+                    Ok((
+                        Report {
+                            id: row.get(0)?,
+                            message_id: row.get(1)?,
+                            reporter_id: row.get(2)?,
+                            reason: row.get(3)?,
+                            created_at: row.get(4)?,
+                        },
+                        row.get(5)?,
+                        row.get(6)?,
+                        row.get(7)?,
+                    ))
+                })?
+                .collect();
+            rows
+        })
+        .await
+        .map_err(|err| {
+            tracing::error!("failed to read reports: {err}");
+            AppError::new(StatusCode::INTERNAL_SERVER_ERROR, "failed to read reports")
+        })?;
+
+    let reports = rows
+        .into_iter()
+        .map(|(report, text, text_nonce, compressed)| {
+            let message_text = messages::open_text(
+                state.cipher.as_ref(),
+                &report.message_id,
+                text,
+                text_nonce,
+                compressed,
+            )?;
+            Ok(ReportWithMessage {
+                report,
+                message_text,
+            })
+        })
+        .collect::<Result<Vec<ReportWithMessage>, AppError>>()?;
+
+    Ok((StatusCode::OK, Json(reports)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reason_out_of_bounds_is_a_validation_error() {
+        let state = crate::state::test_state(|_| {});
+        for reason in [String::from("  "), "a".repeat(MAX_REASON_LEN + 1)] {
+            let Err(err) = create_report(
+                State(state.clone()),
+                Path(uuidv7::create()),
+                Json(CreateReport {
+                    reporter_id: String::from("ann"),
+                    reason,
+                }),
+            )
+            .await
+            else {
+                panic!("a report with an out of bounds reason was stored");
+            };
+            assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(err.field_errors[0].field, "reason");
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use axum::{extract::Request, http::HeaderValue};
+use tower_http::request_id::{MakeRequestId, RequestId};
+use tracing::Span;
+
+// Gives requests that arrive without an `X-Request-Id` a uuidv7, like every other id we mint
+#[derive(Clone, Copy)]
+pub struct MakeRequestUuid;
+
+impl MakeRequestId for MakeRequestUuid {
+    fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
+        // uuids are plain ascii, so always a valid header value
+        let id = HeaderValue::from_str(&uuidv7::create()).unwrap();
+        Some(RequestId::new(id))
+    }
+}
+
+// The span every log event of a request is recorded under, tagged with its `X-Request-Id`
+pub fn span(request: &Request) -> Span {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        uri = %request.uri(),
+    )
+}
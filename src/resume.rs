@@ -0,0 +1,157 @@
+use axum::{http::StatusCode, Error};
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    crypto,
+    deliveries::MAX_REPLAY,
+    error::AppError,
+    messages::{StoredMessage, MESSAGE_COLUMNS},
+    msg,
+    state::AppState,
+};
+
+// how long after a socket closes its resume token can be used
+const RESUME_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+// Per channel, the highest `seq` a socket has been sent. Channels missing from it have had nothing sent
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Delivered(HashMap<String, u64>);
+
+impl Delivered {
+    // whether `msg` or a later message in its channel has been sent
+    pub fn covers(&self, msg: &msg::Message) -> bool {
+        self.0.get(&msg.channel).is_some_and(|&seq| msg.seq <= seq)
+    }
+
+    // Records `msg` as sent, `false` if it was already covered
+    pub fn advance(&mut self, msg: &msg::Message) -> bool {
+        if self.covers(msg) {
+            return false;
+        }
+        self.0.insert(msg.channel.clone(), msg.seq);
+        true
+    }
+}
+
+pub fn new_token() -> String {
+    crypto::random_token()
+}
+
+// Where a fresh socket starts off, the newest message in every channel. Taken before subscribing to the
+// live feed, `missed` then fills the gap
+pub async fn heads(state: &AppState) -> Delivered {
+    state
+        .call_db("resume_heads", |conn| {
+            let heads = conn
+                .prepare("SELECT channel, next - 1 FROM channel_seq;")
+                .unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<Result<HashMap<String, u64>, rusqlite::Error>>()
+                .unwrap();
+            Delivered(heads)
+        })
+        .await
+}
+
+// Where a reconnecting socket picks up from, the position saved under `token`. A token only works once
+pub async fn take(state: &AppState, token: String) -> Result<Delivered, AppError> {
+    let now = msg::now_millis();
+    state
+        .call_db("resume_take", move |conn| {
+            // the expiry sweep may not have caught up with a stale token yet
+            let delivered: Option<String> = conn
+                .query_row(
+                    "DELETE FROM resume_tokens WHERE token = ? RETURNING delivered, expires_at;",
+                    [token],
+                    |row| Ok((row.get(0)?, row.get::<_, u64>(1)?)),
+                )
+                .optional()
+                .unwrap()
+                .filter(|(_, expires_at)| *expires_at > now)
+                .map(|(delivered, _)| delivered);
+            let Some(delivered) = delivered else {
+                return Err(AppError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "invalid or expired resume token",
+                ));
+            };
+
+            Ok(serde_json::from_str(&delivered).unwrap())
+        })
+        .await
+}
+
+// Messages past `delivered` in every channel, oldest first per channel
+pub async fn missed(state: &AppState, delivered: Delivered) -> Result<Vec<msg::Message>, AppError> {
+    let now = msg::now_millis();
+    let stored = state
+        .call_db(
+            "resume_missed",
+            move |conn| -> Result<Vec<StoredMessage>, Error> {
+                // only channels whose head moved past the saved position have anything to send
+                let behind = conn
+                    .prepare("SELECT channel, next - 1 FROM channel_seq;")
+                    .unwrap()
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .unwrap()
+                    .collect::<Result<Vec<(String, u64)>, rusqlite::Error>>()
+                    .unwrap()
+                    .into_iter()
+                    .filter_map(|(channel, head)| {
+                        let seq = delivered.0.get(&channel).copied().unwrap_or_default();
+                        (head > seq).then_some((channel, seq))
+                    });
+
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT {MESSAGE_COLUMNS} FROM messages
+                         WHERE channel = ? AND seq > ? AND (expires_at IS NULL OR expires_at > ?)
+                         ORDER BY seq LIMIT ?;"
+                    ))
+                    .unwrap();
+                let mut stored = vec![];
+                for (channel, seq) in behind {
+                    let left = MAX_REPLAY - stored.len() as u32;
+                    if left == 0 {
+                        break;
+                    }
+                    stored.extend(
+                        stmt.query_map(
+                            rusqlite::params![channel, seq, now, left],
+                            StoredMessage::from_row,
+                        )
+                        .unwrap()
+                        .collect::<Result<Vec<StoredMessage>, rusqlite::Error>>()
+                        .unwrap(),
+                    );
+                }
+
+                Ok(stored)
+            },
+        )
+        .await
+        .unwrap();
+
+    stored
+        .into_iter()
+        .map(|stored| stored.open(state))
+        .collect()
+}
+
+// Saves where a closing socket got to under `token`, for a reconnect to pick up from
+pub async fn save(state: &AppState, token: String, delivered: Delivered) {
+    let expires_at = msg::now_millis() + RESUME_TOKEN_TTL.as_millis() as u64;
+    let delivered = serde_json::to_string(&delivered).unwrap();
+    state
+        .call_db("resume_save", move |conn| {
+            conn.execute(
+                "INSERT INTO resume_tokens (token, delivered, expires_at) VALUES (?, ?, ?)",
+                rusqlite::params![token, delivered, expires_at],
+            )
+            .unwrap();
+        })
+        .await;
+}
@@ -0,0 +1,175 @@
+use axum::{
+    body::to_bytes,
+    extract::{Query, State},
+    http::{HeaderMap, Uri},
+    response::Response,
+};
+use serde_json::Value;
+use std::{fmt::Display, sync::Arc};
+
+use crate::{body::JsonOrForm, crypto, messages, msg, state::AppState, users};
+
+// Run with `--selftest` after a deploy: checks the schema is current, then creates a user, posts a message
+// as them and reads it back through the same handlers the API serves, printing a line per step.
+// Everything it creates goes under a `selftest-` name of its own and is deleted afterwards
+pub async fn run(state: Arc<AppState>) -> bool {
+    let name = format!("selftest-{}", crypto::random_hex(4));
+    let passed = round_trip(&state, &name).await;
+    // whatever the round trip got through, it leaves nothing behind
+    let cleaned_up = report("clean up", clean_up(&state, name).await).is_some();
+
+    let passed = passed && cleaned_up;
+    println!("selftest {}", if passed { "passed" } else { "failed" });
+    passed
+}
+
+// Prints how the step went, handing back its result when it passed
+fn report<T, E: Display>(step: &str, result: Result<T, E>) -> Option<T> {
+    match result {
+        Ok(value) => {
+            println!("ok    {step}");
+            Some(value)
+        }
+        Err(err) => {
+            println!("FAIL  {step}: {err}");
+            None
+        }
+    }
+}
+
+// `name` is used for both the test user and the test channel, each step only runs if the one before passed
+async fn round_trip(state: &Arc<AppState>, name: &str) -> bool {
+    if report("schema is current", check_schema(state).await).is_none() {
+        return false;
+    }
+    let Some(user) = report("create user", create_user(state, name).await) else {
+        return false;
+    };
+    let Some(sent) = report("create message", create_message(state, &user, name).await) else {
+        return false;
+    };
+    report("read message back", read_back(state, name, &sent).await).is_some()
+}
+
+async fn check_schema(state: &AppState) -> Result<(), String> {
+    let version = state
+        .call_db("selftest_schema", |conn| {
+            conn.query_row("PRAGMA user_version", [], |row| row.get::<_, usize>(0))
+        })
+        .await
+        .map_err(|err| format!("database unavailable: {err}"))?;
+    let latest_version = crate::migrations().len();
+    if version != latest_version {
+        return Err(format!(
+            "database schema is at version {version}, expected {latest_version}"
+        ));
+    }
+    Ok(())
+}
+
+async fn create_user(state: &Arc<AppState>, name: &str) -> Result<users::User, String> {
+    let payload = users::CreateUser {
+        username: name.to_string(),
+    };
+    let (_, user) = users::create_user(State(state.clone()), JsonOrForm(payload))
+        .await
+        .map_err(|err| err.message)?;
+    Ok(user.0)
+}
+
+async fn create_message(
+    state: &Arc<AppState>,
+    user: &users::User,
+    channel: &str,
+) -> Result<Value, String> {
+    // set up directly, so the test passes whether or not posting may create channels
+    let name = channel.to_string();
+    let now = msg::now_millis();
+    state
+        .call_db("selftest_channel", move |conn| {
+            conn.execute(
+                "INSERT INTO channels (name, created_at) VALUES (?, ?)",
+                rusqlite::params![name, now],
+            )
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let payload = msg::CreateMessage {
+        id: None,
+        time: now,
+        user_id: user.id.clone(),
+        username: user.username.clone(),
+        text: String::from("selftest"),
+        channel: channel.to_string(),
+        reply_to: None,
+        quote_of: None,
+        ttl_secs: None,
+        format: msg::MessageFormat::Plain,
+        metadata: None,
+        idempotency_key: None,
+    };
+    let response = messages::create_message(
+        State(state.clone()),
+        None,
+        Query::try_from_uri(&Uri::from_static("/messages")).unwrap(),
+        HeaderMap::new(),
+        JsonOrForm(payload),
+    )
+    .await
+    .map_err(|err| err.message)?;
+    body_json(response).await
+}
+
+async fn read_back(state: &Arc<AppState>, channel: &str, sent: &Value) -> Result<(), String> {
+    let uri: Uri = format!("/messages?channel={channel}").parse().unwrap();
+    let response = messages::get_messages(
+        State(state.clone()),
+        Query::try_from_uri(&uri).unwrap(),
+        HeaderMap::new(),
+    )
+    .await
+    .map_err(|err| err.message)?;
+    let page = body_json(response).await?;
+
+    let read = page
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|msg| msg["id"] == sent["id"])
+        .ok_or("the message isn't in the channel's history")?;
+    // the interceptor or word filter may have changed the text, what was stored is what was returned
+    if read["text"] != sent["text"] {
+        return Err(format!(
+            "read back text {} instead of {}",
+            read["text"], sent["text"]
+        ));
+    }
+    Ok(())
+}
+
+async fn body_json(response: Response) -> Result<Value, String> {
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|err| err.to_string())?;
+    if !status.is_success() {
+        return Err(format!("{status}: {}", String::from_utf8_lossy(&body)));
+    }
+    serde_json::from_slice(&body).map_err(|err| err.to_string())
+}
+
+async fn clean_up(state: &AppState, name: String) -> Result<(), rusqlite::Error> {
+    state
+        .call_db("selftest_clean_up", move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM messages WHERE channel = ?", [&name])?;
+            // the delete above leaves tombstones for `GET /messages/delta`, which have nothing left to report
+            tx.execute("DELETE FROM message_tombstones WHERE channel = ?", [&name])?;
+            tx.execute("DELETE FROM channel_seq WHERE channel = ?", [&name])?;
+            tx.execute("DELETE FROM channels WHERE name = ?", [&name])?;
+            tx.execute("DELETE FROM users WHERE username = ?", [&name])?;
+            tx.commit()
+        })
+        .await
+}
@@ -0,0 +1,160 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc, RwLock,
+    },
+    time::Instant,
+};
+use tokio::sync::broadcast;
+
+use crate::{
+    config::Config,
+    connections::LiveConnections,
+    crypto::TextCipher,
+    filter::WordFilter,
+    ingest::IngestQueue,
+    interceptor::{MessageInterceptor, NoopInterceptor},
+    recent::RecentMessages,
+    ws,
+};
+
+// how many frames the broadcast channel buffers for a slow socket before it lags, unless resized
+const DEFAULT_BROADCAST_CAPACITY: usize = 16;
+
+// The channel used to send frames to all connected clients, with the capacity it was created with
+pub struct Broadcast {
+    pub tx: broadcast::Sender<ws::ServerFrame>,
+    pub capacity: usize,
+}
+
+impl Broadcast {
+    fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx, capacity }
+    }
+}
+
+pub struct AppState {
+    // swapped out by `POST /admin/broadcast/resize`, go through `broadcast` and `subscribe`
+    pub broadcast: RwLock<Broadcast>,
+    pub conn: tokio_rusqlite::Connection,
+    pub config: Config,
+    // set when message text is encrypted at rest
+    pub cipher: Option<TextCipher>,
+    // set when a blocklist is configured
+    pub word_filter: Option<WordFilter>,
+    // set unless RECENT_MESSAGES=0, shared with the db calls that keep it current
+    pub recent: Option<Arc<RecentMessages>>,
+    // set when INGEST_QUEUE is, socket messages go through it to be stored in batches
+    pub ingest: Option<IngestQueue>,
+    // runs on every message before it's stored, install your own with `with_interceptor` to pre-process messages
+    pub interceptor: Arc<dyn MessageInterceptor>,
+    // number of open WebSocket connections
+    pub connections: AtomicUsize,
+    // the sockets open on this instance, for admins to look at and kick
+    pub live_connections: LiveConnections,
+    // set once a shutdown signal arrives, `/ready` and `/live` answer `503` from then on
+    pub shutting_down: AtomicBool,
+}
+
+impl AppState {
+    pub fn new(
+        conn: tokio_rusqlite::Connection,
+        config: Config,
+        ingest: Option<IngestQueue>,
+    ) -> Self {
+        let cipher = config
+            .message_encryption_key
+            .as_deref()
+            .map(TextCipher::from_base64_key);
+        let word_filter = config
+            .blocklist_path
+            .as_deref()
+            .map(|path| WordFilter::load(path, config.blocklist_mode));
+        let recent = config
+            .recent_messages
+            .map(|capacity| Arc::new(RecentMessages::new(capacity, config.recent_channels)));
+        Self {
+            broadcast: RwLock::new(Broadcast::new(DEFAULT_BROADCAST_CAPACITY)),
+            conn,
+            config,
+            cipher,
+            word_filter,
+            recent,
+            ingest,
+            interceptor: Arc::new(NoopInterceptor),
+            connections: AtomicUsize::new(0),
+            live_connections: LiveConnections::default(),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    // Runs `interceptor` on every message before it's stored, instead of letting them all through
+    pub fn with_interceptor(self, interceptor: Arc<dyn MessageInterceptor>) -> Self {
+        Self {
+            interceptor,
+            ..self
+        }
+    }
+}
+
+impl AppState {
+    // Sends `frame` to every connected socket, returning how many received it
+    pub fn broadcast(&self, frame: ws::ServerFrame) -> usize {
+        // no subscribers is not an error
+        self.broadcast.read().unwrap().tx.send(frame).unwrap_or(0)
+    }
+
+    // The current broadcast sender, for sending from a db call so frames go out in commit order
+    pub fn broadcaster(&self) -> broadcast::Sender<ws::ServerFrame> {
+        self.broadcast.read().unwrap().tx.clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ws::ServerFrame> {
+        self.broadcast.read().unwrap().tx.subscribe()
+    }
+
+    // Replaces the broadcast channel with one of `capacity`. Dropping the old sender closes every socket's
+    // subscription once it has drained, so connected sockets close and have to reconnect
+    pub fn resize_broadcast(&self, capacity: usize) {
+        *self.broadcast.write().unwrap() = Broadcast::new(capacity);
+    }
+
+    // Runs `function` on the db connection, logging it under `label` when it's slower than SLOW_QUERY_MS
+    pub async fn call_db<F, R>(&self, label: &'static str, function: F) -> R
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let start = Instant::now();
+        let result = self.conn.call_unwrap(function).await;
+        let elapsed = start.elapsed();
+
+        if let Some(threshold) = self.config.slow_query_threshold {
+            if elapsed >= threshold {
+                tracing::warn!(
+                    label,
+                    duration_ms = elapsed.as_millis() as u64,
+                    "slow query"
+                );
+            }
+        }
+
+        result
+    }
+}
+
+// A state over a fresh in-memory db with every migration applied, for tests. The per-user rate limits
+// are off so a test can send a burst of messages, `configure` changes whatever else the test needs
+#[cfg(test)]
+pub fn test_state(configure: impl FnOnce(&mut Config)) -> Arc<AppState> {
+    let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+    rusqlite_migration::Migrations::new(crate::migrations())
+        .to_latest(&mut conn)
+        .unwrap();
+    let mut config = Config::from_env().unwrap();
+    config.max_messages_per_minute = None;
+    config.duplicate_window = None;
+    configure(&mut config);
+    Arc::new(AppState::new(conn.into(), config, None))
+}
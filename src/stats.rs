@@ -0,0 +1,205 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Error, Json, Router,
+};
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    error::AppError,
+    messages::{message_filters, where_clause},
+    msg,
+    state::AppState,
+};
+
+// rows a leaderboard returns when the request doesn't say, fewer than a usual page
+const DEFAULT_TOP_USERS: u32 = 10;
+
+// most buckets one activity query spans, a month of hours or a few years of days
+const MAX_BUCKETS: u64 = 1000;
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/stats/top-users", get(get_top_users))
+        .route("/stats/activity", get(get_activity))
+}
+
+#[derive(Deserialize)]
+struct TopUsersQuery {
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct TopUser {
+    user_id: String,
+    username: String,
+    message_count: u64,
+}
+
+// Users with the most messages in a channel, the default channel when none is given
+async fn get_top_users(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TopUsersQuery>,
+) -> Result<(StatusCode, Json<Vec<TopUser>>), AppError> {
+    let channel = query
+        .channel
+        .map(|channel| msg::normalize_channel(&channel))
+        .unwrap_or_else(|| state.config.default_channel.clone());
+    let limit = state
+        .config
+        .pagination
+        .limit(Some(query.limit.unwrap_or(DEFAULT_TOP_USERS)))?;
+
+    let (filters, mut params) =
+        message_filters(msg::now_millis(), Some(&channel), None, None, None);
+    params.push(Value::Integer(limit.into()));
+    let users = state
+        .call_db(
+            "get_top_users",
+            move |conn| -> Result<Vec<TopUser>, Error> {
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT user_id, username, COUNT(*) c FROM messages{}
+                         GROUP BY user_id ORDER BY c DESC, user_id LIMIT ?;",
+                        where_clause(&filters)
+                    ))
+                    .unwrap();
+                let users = stmt
+                    .query_map(rusqlite::params_from_iter(&params), |row| {
+                        Ok(TopUser {
+                            user_id: row.get(0)?,
+                            username: row.get(1)?,
+                            message_count: row.get(2)?,
+                        })
+                    })
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<TopUser>, rusqlite::Error>>()
+                    .unwrap();
+
+                Ok(users)
+            },
+        )
+        .await
+        .unwrap();
+
+    Ok((StatusCode::OK, Json(users)))
+}
+
+// How long each activity count covers, buckets start on the UTC hour or day
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Bucket {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    fn millis(self) -> u64 {
+        match self {
+            Bucket::Hour => 60 * 60 * 1000,
+            Bucket::Day => 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ActivityQuery {
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    bucket: Bucket,
+    // milliseconds since the Unix epoch, the last day of buckets unless given
+    #[serde(default)]
+    from: Option<u64>,
+    // until now unless given
+    #[serde(default)]
+    to: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Activity {
+    // when the bucket starts, in milliseconds since the Unix epoch
+    start: u64,
+    message_count: u64,
+}
+
+// Messages sent in a channel per hour or day, the default channel when none is given.
+// Every bucket from `from` to `to` is returned, oldest first, including the empty ones
+async fn get_activity(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ActivityQuery>,
+) -> Result<(StatusCode, Json<Vec<Activity>>), AppError> {
+    let channel = query
+        .channel
+        .map(|channel| msg::normalize_channel(&channel))
+        .unwrap_or_else(|| state.config.default_channel.clone());
+    let size = query.bucket.millis();
+    let to = query.to.unwrap_or_else(msg::now_millis);
+    let from = query.from.unwrap_or(to.saturating_sub(24 * size));
+    // sqlite integers are signed
+    if i64::try_from(from).is_err() || i64::try_from(to).is_err() {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "from and to are out of range",
+        ));
+    }
+    if from > to {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "from must not be after to",
+        ));
+    }
+    let first = from / size * size;
+    if (to - first) / size >= MAX_BUCKETS {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_BUCKETS} buckets can be asked for at once"),
+        ));
+    }
+    let now = msg::now_millis();
+
+    let counts = state
+        .call_db(
+            "get_activity",
+            move |conn| -> Result<Vec<(u64, u64)>, Error> {
+                let mut stmt = conn
+                    .prepare(
+                        "SELECT time / ?1 * ?1 start, COUNT(*) FROM messages
+                         WHERE channel = ?2 AND time BETWEEN ?3 AND ?4 AND (expires_at IS NULL OR expires_at > ?5)
+                         GROUP BY start ORDER BY start;",
+                    )
+                    .unwrap();
+                let counts = stmt
+                    .query_map(rusqlite::params![size, channel, from, to, now], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<(u64, u64)>, rusqlite::Error>>()
+                    .unwrap();
+
+                Ok(counts)
+            },
+        )
+        .await
+        .unwrap();
+
+    let mut counts = counts.into_iter().peekable();
+    let activity = (first..=to)
+        .step_by(size as usize)
+        .map(|start| Activity {
+            start,
+            message_count: counts
+                .next_if(|&(bucket, _)| bucket == start)
+                .map_or(0, |(_, count)| count),
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(activity)))
+}
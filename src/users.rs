@@ -0,0 +1,566 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    routing::{get, patch, post},
+    Error, Json, Router,
+};
+use rusqlite::{types::Value, ErrorCode, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    auth::Admin,
+    body::JsonOrForm,
+    channels::{Channel, CHANNEL_COLUMNS},
+    config::Config,
+    crypto,
+    error::{AppError, ValidationError},
+    messages::{message_filters, where_clause, StoredMessage, MESSAGE_COLUMNS},
+    msg,
+    state::AppState,
+};
+
+// guest names are 32 random bits, so a few draws always find a free one
+const MAX_GUEST_NAME_ATTEMPTS: usize = 5;
+
+// longest status a user may set, in characters
+const MAX_STATUS_LEN: usize = 100;
+
+// most usernames `POST /users/resolve` looks up at once
+const MAX_RESOLVE_USERNAMES: usize = 100;
+
+// Columns selected by every user query, in the order `User::from_row` reads them
+const USER_COLUMNS: &str = "id, username, color, status";
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        // `POST /users` goes to `create_user`
+        .route("/users", post(create_user))
+        .route("/users", get(get_users))
+        .route("/users/resolve", post(resolve_users))
+        .route("/users/:id", patch(update_user))
+        .route("/users/:id/ban", post(ban_user).delete(unban_user))
+        .route("/users/:id/messages", get(get_user_messages))
+        .route("/users/:id/channels", get(get_user_channels))
+}
+
+pub async fn create_user(
+    State(state): State<Arc<AppState>>,
+    // this argument tells axum to parse the request body
+    // as JSON or a form into a `CreateUser` type
+    JsonOrForm(payload): JsonOrForm<CreateUser>,
+) -> Result<(StatusCode, Json<User>), AppError> {
+    // generated guest names are known to be valid
+    let anonymous = payload.username.is_empty() && state.config.allow_anon_users;
+
+    let mut errors = ValidationError::default();
+    if !anonymous {
+        check_username(&state.config, &payload.username, &mut errors);
+    }
+    errors.check()?;
+
+    let id = uuidv7::create();
+    let id_copy = id.clone();
+
+    // Add user to users table, drawing another guest name if the generated one is taken
+    let username = state
+        .call_db("create_user", move |conn| {
+            for _ in 0..MAX_GUEST_NAME_ATTEMPTS {
+                let username = if anonymous {
+                    format!("guest-{}", crypto::random_hex(4))
+                } else {
+                    payload.username.clone()
+                };
+                match conn.execute(
+                    "INSERT INTO users (id, username) VALUES (?, ?)",
+                    [&id_copy, &username],
+                ) {
+                    Ok(_) => return Ok(username),
+                    Err(err) if err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) => {
+                        if anonymous {
+                            continue;
+                        }
+                        return Err(AppError::new(StatusCode::CONFLICT, "username taken"));
+                    }
+                    Err(err) => {
+                        tracing::error!("failed to create user: {err}");
+                        return Err(AppError::new(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "failed to create user",
+                        ));
+                    }
+                }
+            }
+            Err(AppError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not generate a unique guest username",
+            ))
+        })
+        .await?;
+
+    let user = User {
+        id,
+        username,
+        color: None,
+        status: None,
+    };
+
+    // this will be converted into a JSON response
+    // with a status code of `201 Created`
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+// Adds the ways `username` is unfit for a new user to `errors`
+pub fn check_username(config: &Config, username: &str, errors: &mut ValidationError) {
+    let username_len = username.chars().count();
+    if username_len == 0 || username_len > config.max_username_len {
+        errors.add(
+            "username",
+            format!(
+                "must be between 1 and {} characters",
+                config.max_username_len
+            ),
+        );
+    }
+    if username
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control())
+    {
+        errors.add(
+            "username",
+            "must not contain whitespace or control characters",
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct ResolveUsers {
+    usernames: Vec<String>,
+}
+
+// Maps each username to its user's id, so a message's @-mentions resolve in one round trip.
+// Usernames no user has are left out
+async fn resolve_users(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ResolveUsers>,
+) -> Result<(StatusCode, Json<HashMap<String, String>>), AppError> {
+    if payload.usernames.len() > MAX_RESOLVE_USERNAMES {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("at most {MAX_RESOLVE_USERNAMES} usernames can be resolved at once"),
+        ));
+    }
+    if payload.usernames.is_empty() {
+        return Ok((StatusCode::OK, Json(HashMap::new())));
+    }
+
+    let usernames = payload.usernames;
+    let ids = state
+        .call_db("resolve_users", move |conn| {
+            // one bound `?` per username, the usernames themselves never reach the SQL text
+            let placeholders = vec!["?"; usernames.len()].join(", ");
+            let mut stmt = conn
+                .prepare(&format!(
+                    "SELECT username, id FROM users WHERE username IN ({placeholders});"
+                ))
+                .unwrap();
+            stmt.query_map(rusqlite::params_from_iter(&usernames), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap()
+            .collect::<Result<HashMap<String, String>, rusqlite::Error>>()
+            .unwrap()
+        })
+        .await;
+
+    Ok((StatusCode::OK, Json(ids)))
+}
+
+#[derive(Deserialize, Serialize)]
+struct GetUsersQuery {
+    // only return users whose username sorts after this cursor, taken from `X-Next-Cursor`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    after: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limit: Option<u32>,
+}
+
+// Users ordered by username, with `X-Next-Cursor` and a `Link: rel="next"` header when there are more
+async fn get_users(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<GetUsersQuery>,
+) -> Result<(StatusCode, HeaderMap, Json<Vec<User>>), AppError> {
+    let limit = state.config.pagination.limit(query.limit)?;
+    // usernames are unique, so they're a stable cursor on their own
+    let after = query.after.clone().unwrap_or_default();
+
+    let mut users = state
+        .call_db("get_users", move |conn| -> Result<Vec<User>, Error> {
+            // one extra row tells us whether there is a next page
+            let mut stmt = conn
+                .prepare(&format!(
+                    "SELECT {USER_COLUMNS} FROM users WHERE username > ? ORDER BY username LIMIT ?;"
+                ))
+                .unwrap();
+            let users = stmt
+                .query_map(rusqlite::params![after, limit + 1], User::from_row)
+                .unwrap()
+                .collect::<std::result::Result<Vec<User>, rusqlite::Error>>()
+                .unwrap();
+
+            Ok(users)
+        })
+        .await
+        .unwrap();
+
+    let has_more = users.len() > limit as usize;
+    users.truncate(limit as usize);
+
+    let mut headers = HeaderMap::new();
+    if let Some(last) = users.last().filter(|_| has_more) {
+        let next = serde_urlencoded::to_string(GetUsersQuery {
+            after: Some(last.username.clone()),
+            limit: Some(limit),
+        })
+        .unwrap();
+        // usernames can't contain control characters, so they're always a valid header value
+        headers.insert(
+            "x-next-cursor",
+            HeaderValue::from_str(&last.username).unwrap(),
+        );
+        headers.insert(
+            header::LINK,
+            HeaderValue::from_str(&format!("</users?{next}>; rel=\"next\"")).unwrap(),
+        );
+    }
+
+    Ok((StatusCode::OK, headers, Json(users)))
+}
+
+// Sets how the user's name is shown, fields left out are kept and an empty string clears one.
+// Like posting as a user, this isn't authenticated
+async fn update_user(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    JsonOrForm(payload): JsonOrForm<UpdateUser>,
+) -> Result<(StatusCode, Json<User>), AppError> {
+    let mut errors = ValidationError::default();
+    let color = payload.color.map(|color| color.to_lowercase());
+    if color
+        .as_deref()
+        .is_some_and(|color| !color.is_empty() && !is_hex_color(color))
+    {
+        errors.add("color", "must be a hex color like #1a2b3c");
+    }
+    if let Some(status) = &payload.status {
+        if status.chars().count() > MAX_STATUS_LEN {
+            errors.add(
+                "status",
+                format!("must be at most {MAX_STATUS_LEN} characters"),
+            );
+        }
+        if status.chars().any(char::is_control) {
+            errors.add("status", "must not contain control characters");
+        }
+    }
+    errors.check()?;
+
+    let user = state
+        .call_db("update_user", move |conn| {
+            conn.query_row(
+                &format!(
+                    "UPDATE users SET
+                         color = CASE WHEN ?1 IS NULL THEN color ELSE NULLIF(?1, '') END,
+                         status = CASE WHEN ?2 IS NULL THEN status ELSE NULLIF(?2, '') END
+                     WHERE id = ?3 RETURNING {USER_COLUMNS}"
+                ),
+                rusqlite::params![color, payload.status, id],
+                User::from_row,
+            )
+            .optional()
+            .unwrap()
+        })
+        .await
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "user not found"))?;
+
+    Ok((StatusCode::OK, Json(user)))
+}
+
+// `#` and six hex digits
+fn is_hex_color(color: &str) -> bool {
+    color
+        .strip_prefix('#')
+        .is_some_and(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[derive(Deserialize)]
+struct UserMessagesQuery {
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+// The user's newest messages across all channels, newest first
+async fn get_user_messages(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<UserMessagesQuery>,
+) -> Result<(StatusCode, Json<Vec<msg::Message>>), AppError> {
+    let limit = state.config.pagination.limit(query.limit)?;
+
+    let (filters, mut params) = message_filters(msg::now_millis(), None, Some(id), None, None);
+    params.push(Value::Integer(limit.into()));
+    let messages = state
+        .call_db(
+            "get_user_messages",
+            move |conn| -> Result<Vec<StoredMessage>, Error> {
+                let mut stmt = conn
+                    .prepare(&format!(
+                        "SELECT {MESSAGE_COLUMNS} FROM messages{} ORDER BY time DESC, id DESC LIMIT ?;",
+                        where_clause(&filters)
+                    ))
+                    .unwrap();
+                let messages = stmt
+                    .query_map(rusqlite::params_from_iter(&params), StoredMessage::from_row)
+                    .unwrap()
+                    .collect::<std::result::Result<Vec<StoredMessage>, rusqlite::Error>>()
+                    .unwrap();
+
+                Ok(messages)
+            },
+        )
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|stored| stored.open(&state))
+        .collect::<Result<Vec<msg::Message>, AppError>>()?;
+
+    Ok((StatusCode::OK, Json(messages)))
+}
+
+// Channels the user created by posting to them, oldest first
+async fn get_user_channels(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> (StatusCode, Json<Vec<Channel>>) {
+    let channels = state
+        .call_db("get_user_channels", move |conn| -> Result<Vec<Channel>, Error> {
+            let mut stmt = conn
+                .prepare(&format!(
+                    "SELECT {CHANNEL_COLUMNS} FROM channels WHERE created_by = ? ORDER BY created_at, name;"
+                ))
+                .unwrap();
+            let channels = stmt
+                .query_map([id], Channel::from_row)
+                .unwrap()
+                .collect::<std::result::Result<Vec<Channel>, rusqlite::Error>>()
+                .unwrap();
+
+            Ok(channels)
+        })
+        .await
+        .unwrap();
+
+    (StatusCode::OK, Json(channels))
+}
+
+// Stops the user from posting over REST or the WebSocket until `until`, or for good when it's unset
+async fn ban_user(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(payload): Json<BanUser>,
+) -> Result<(StatusCode, Json<Ban>), AppError> {
+    let mut errors = ValidationError::default();
+    if let Some(until) = payload.until {
+        // the db stores times as signed 64-bit integers
+        if i64::try_from(until).is_err() {
+            errors.add("until", "is out of range");
+        } else if until <= msg::now_millis() {
+            errors.add("until", "must be in the future");
+        }
+    }
+    errors.check()?;
+
+    let ban = Ban {
+        user_id: id,
+        until: payload.until,
+    };
+
+    let ban_copy = ban.clone();
+    state
+        .call_db("ban_user", move |conn| {
+            let updated = conn
+                .execute(
+                    "UPDATE users SET banned = 1, banned_until = ? WHERE id = ?",
+                    rusqlite::params![ban_copy.until, ban_copy.user_id],
+                )
+                .map_err(|err| {
+                    tracing::error!("failed to ban user {}: {err}", ban_copy.user_id);
+                    AppError::new(StatusCode::INTERNAL_SERVER_ERROR, "failed to ban user")
+                })?;
+            if updated == 0 {
+                return Err(AppError::new(StatusCode::NOT_FOUND, "user not found"));
+            }
+
+            Ok(())
+        })
+        .await?;
+
+    Ok((StatusCode::OK, Json(ban)))
+}
+
+async fn unban_user(
+    _admin: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state
+        .call_db("unban_user", move |conn| {
+            let updated = conn
+                .execute(
+                    "UPDATE users SET banned = 0, banned_until = NULL WHERE id = ?",
+                    [id],
+                )
+                .unwrap();
+            if updated == 0 {
+                return Err(AppError::new(StatusCode::NOT_FOUND, "user not found"));
+            }
+
+            Ok(())
+        })
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// the input to our `create_user` handler
+#[derive(Deserialize)]
+pub struct CreateUser {
+    // may be left out when ALLOW_ANON_USERS is set
+    #[serde(default)]
+    pub username: String,
+}
+
+// the output to our `create_user` handler
+#[derive(Serialize, Clone)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    // `#rrggbb`, clients render the username in it
+    pub color: Option<String>,
+    pub status: Option<String>,
+}
+
+impl User {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            username: row.get(1)?,
+            color: row.get(2)?,
+            status: row.get(3)?,
+        })
+    }
+}
+
+// Fields left out of `PATCH /users/:id` are kept
+#[derive(Deserialize)]
+pub struct UpdateUser {
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BanUser {
+    // milliseconds since the Unix epoch when the ban lifts, send `{}` for a permanent ban
+    #[serde(default)]
+    pub until: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Ban {
+    pub user_id: String,
+    pub until: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+
+    async fn insert_user(state: &AppState, id: &str, username: &str) {
+        let (id, username) = (id.to_string(), username.to_string());
+        state
+            .call_db("insert_user", move |conn| {
+                conn.execute(
+                    "INSERT INTO users (id, username) VALUES (?, ?)",
+                    [id, username],
+                )
+                .unwrap();
+            })
+            .await;
+    }
+
+    async fn ban(state: &Arc<AppState>, until: Option<u64>) -> Result<Ban, AppError> {
+        let (_, Json(ban)) = ban_user(
+            Admin,
+            State(state.clone()),
+            Path(String::from("ann")),
+            Json(BanUser { until }),
+        )
+        .await?;
+        Ok(ban)
+    }
+
+    #[tokio::test]
+    async fn ban_until_must_be_a_future_time_the_db_can_store() {
+        let state = test_state(|_| {});
+        insert_user(&state, "ann", "ann").await;
+
+        let err = ban(&state, Some(u64::MAX)).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        let err = ban(&state, Some(1)).await.unwrap_err();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let until = msg::now_millis() + 60_000;
+        assert_eq!(ban(&state, Some(until)).await.unwrap().until, Some(until));
+        assert_eq!(ban(&state, None).await.unwrap().until, None);
+    }
+
+    async fn list_users(
+        state: &Arc<AppState>,
+        after: Option<&str>,
+    ) -> (Vec<String>, Option<String>) {
+        let query = GetUsersQuery {
+            after: after.map(String::from),
+            limit: Some(2),
+        };
+        let (_, headers, Json(users)) =
+            get_users(State(state.clone()), Query(query)).await.unwrap();
+        let cursor = headers
+            .get("x-next-cursor")
+            .map(|cursor| cursor.to_str().unwrap().to_string());
+        (
+            users.into_iter().map(|user| user.username).collect(),
+            cursor,
+        )
+    }
+
+    #[tokio::test]
+    async fn users_are_listed_by_username_whatever_order_they_were_created_in() {
+        let state = test_state(|_| {});
+        // ids and usernames sort differently, and neither matches the insert order
+        for (id, username) in [("1", "carol"), ("4", "ann"), ("2", "dave"), ("3", "bob")] {
+            insert_user(&state, id, username).await;
+        }
+
+        let (users, cursor) = list_users(&state, None).await;
+        assert_eq!(users, vec!["ann", "bob"]);
+        assert_eq!(cursor.as_deref(), Some("bob"));
+
+        let (users, cursor) = list_users(&state, cursor.as_deref()).await;
+        assert_eq!(users, vec!["carol", "dave"]);
+        assert_eq!(cursor, None);
+    }
+}
@@ -0,0 +1,603 @@
+use axum::{
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use axum_extra::{headers, TypedHeader};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    net::IpAddr,
+    sync::Mutex,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, watch, Notify},
+    time::Instant,
+};
+
+use crate::{
+    client_ip::ClientIp, connections, deliveries, error::AppError, messages, msg, resume,
+    state::AppState,
+};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/ws", any(ws_handler))
+}
+
+// Frames a client can send over the socket, tagged by their `type` field
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Chat(Box<msg::CreateMessage>),
+    // the client has received message `id`, it won't be resent on reconnect
+    AckDelivery { id: String },
+    // asks for an id to send a chat message under, so the client can render it before the ack
+    ReserveId,
+}
+
+// Frames the server sends to clients, either broadcast or in reply to a `ClientFrame`
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Message(Box<msg::Message>),
+    // `delivered_to` counts the other sockets the message was broadcast to, a rough fan-out
+    Ack {
+        id: String,
+        delivered_to: usize,
+    },
+    Error {
+        message: String,
+        // for errors clients are expected to handle, rather than just show
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<&'static str>,
+    },
+    // the message was removed, because it expired or an admin deleted it
+    Deleted {
+        id: String,
+        channel: String,
+    },
+    // sent on connect, reconnecting with `?resume=<token>` replays what the socket missed once it closes
+    Resume {
+        token: String,
+    },
+    // an id reserved for this socket's next chat message, in reply to `reserve_id`
+    Id {
+        id: String,
+    },
+    // the channel's topic was set, or cleared when `None`
+    Topic {
+        channel: String,
+        topic: Option<String>,
+    },
+    // sent every HEARTBEAT_SECS so clients can estimate their clock offset and notice a stalled feed
+    Heartbeat {
+        server_time: u64,
+    },
+}
+
+// Wire format negotiated through `Sec-WebSocket-Protocol`, JSON unless the client offers msgpack
+#[derive(Clone, Copy)]
+enum Codec {
+    Json,
+    MsgPack,
+}
+
+impl Codec {
+    // in order of preference when a client offers both
+    const PROTOCOLS: [&'static str; 2] = ["chat.msgpack", "chat.json"];
+
+    fn negotiated(socket: &WebSocket) -> Self {
+        match socket
+            .protocol()
+            .and_then(|protocol| protocol.to_str().ok())
+        {
+            Some("chat.msgpack") => Codec::MsgPack,
+            _ => Codec::Json,
+        }
+    }
+
+    fn encode(self, frame: &ServerFrame) -> Message {
+        match self {
+            Codec::Json => Message::Text(serde_json::to_string(frame).unwrap()),
+            // named so the `type` tag and fields keep their keys, like the JSON encoding
+            Codec::MsgPack => Message::Binary(rmp_serde::to_vec_named(frame).unwrap()),
+        }
+    }
+
+    fn decode(self, message: &Message) -> Result<ClientFrame, String> {
+        match (self, message) {
+            (Codec::Json, Message::Text(text)) => {
+                serde_json::from_str(text).map_err(|err| err.to_string())
+            }
+            (Codec::MsgPack, Message::Binary(bytes)) => {
+                rmp_serde::from_slice(bytes).map_err(|err| err.to_string())
+            }
+            (Codec::Json, _) => Err(String::from("expected a text frame")),
+            (Codec::MsgPack, _) => Err(String::from("expected a binary frame")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WsQuery {
+    // identifies the user up front, so messages it hasn't acked yet are resent on connect
+    pub user_id: Option<String>,
+    // the token from a previous socket's `resume` frame
+    pub resume: Option<String>,
+    // messages not acked with `ack_delivery` within ACK_TIMEOUT_SECS are sent once more
+    #[serde(default)]
+    pub resend_unacked: bool,
+}
+
+// most ids a socket may hold reserved without sending a message under them
+const MAX_RESERVED_IDS: usize = 100;
+
+// how long a client turned away by the connection cap is told to wait
+const SERVER_FULL_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+// how long a socket that sent too large a message is kept open for the error to reach the client.
+// Closing with the rest of the message unread resets the connection, which can drop the error unread
+const TOO_LARGE_GRACE: Duration = Duration::from_millis(500);
+
+// Messages sent to a socket that asked for resends, until they're acked or resent
+struct PendingAcks {
+    timeout: Duration,
+    messages: Mutex<HashMap<String, Box<msg::Message>>>,
+    // ids in the order they time out, every message gets the same timeout
+    deadlines: mpsc::UnboundedSender<(Instant, String)>,
+}
+
+impl PendingAcks {
+    fn new(timeout: Duration) -> (Arc<Self>, mpsc::UnboundedReceiver<(Instant, String)>) {
+        let (deadlines, rx) = mpsc::unbounded_channel();
+        let pending = Self {
+            timeout,
+            messages: Mutex::new(HashMap::new()),
+            deadlines,
+        };
+        (Arc::new(pending), rx)
+    }
+
+    fn track(&self, msg: &msg::Message) {
+        self.messages
+            .lock()
+            .unwrap()
+            .insert(msg.id.clone(), Box::new(msg.clone()));
+        let _ = self
+            .deadlines
+            .send((Instant::now() + self.timeout, msg.id.clone()));
+    }
+
+    // Whether the message was waiting on this ack
+    fn ack(&self, id: &str) -> bool {
+        self.messages.lock().unwrap().remove(id).is_some()
+    }
+}
+
+// Sends each message that's still unacked at its deadline once more, then stops waiting on it
+async fn resend_unacked(
+    pending: Arc<PendingAcks>,
+    mut deadlines: mpsc::UnboundedReceiver<(Instant, String)>,
+    sender: mpsc::Sender<ServerFrame>,
+) {
+    while let Some((deadline, id)) = deadlines.recv().await {
+        tokio::time::sleep_until(deadline).await;
+        let msg = pending.messages.lock().unwrap().remove(&id);
+        let Some(msg) = msg else {
+            continue;
+        };
+        if sender.send(ServerFrame::Message(msg)).await.is_err() {
+            break;
+        }
+    }
+}
+
+// One slot of the MAX_CONNECTIONS cap, released on drop however the socket ends
+struct ConnectionSlot(Arc<AppState>);
+
+impl ConnectionSlot {
+    fn acquire(state: &Arc<AppState>) -> Option<Self> {
+        let max_connections = state.config.max_connections;
+        state
+            .connections
+            .fetch_update(
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+                |count| match max_connections {
+                    Some(max) if count >= max => None,
+                    _ => Some(count + 1),
+                },
+            )
+            .ok()?;
+        Some(Self(state.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Reference: https://gist.github.com/hexcowboy/8ebcf13a5d3b681aa6c684ad51dd6e0c
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    ClientIp(addr): ClientIp,
+    Query(query): Query<WsQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    // reserve the slot before upgrading, if the upgrade never happens the slot is dropped with the closure
+    let Some(slot) = ConnectionSlot::acquire(&state) else {
+        return AppError::new(StatusCode::SERVICE_UNAVAILABLE, "server full")
+            .with_retry_after(SERVER_FULL_RETRY_AFTER)
+            .into_response();
+    };
+
+    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
+        user_agent.to_string()
+    } else {
+        String::from("Unknown browser")
+    };
+    println!("{user_agent} at {addr} connected.");
+    // finalize the upgrade process by returning upgrade callback.
+    // we can customize the callback by sending additional info such as address.
+    // a frame over the limit is turned away from its header, before its payload is buffered
+    let max_message_len = state.config.max_ws_message_len;
+    ws.protocols(Codec::PROTOCOLS)
+        .max_frame_size(max_message_len)
+        .max_message_size(max_message_len)
+        .on_upgrade(move |socket| handle_upgrade(socket, addr, user_agent, state, slot, query))
+}
+
+// Stores and broadcasts a chat message sent over the socket. With `own_ids` its id is noted before
+// it's broadcast, which can reach the socket before the insert returns, so only its echo is suppressed
+async fn store_chat(
+    state: &AppState,
+    payload: msg::CreateMessage,
+    own_ids: Option<&Mutex<HashSet<String>>>,
+) -> Result<(msg::Message, usize), AppError> {
+    let prepared = messages::prepare_message(state, payload, false).await?;
+    let id = prepared.id().to_string();
+    if let Some(own_ids) = own_ids {
+        own_ids.lock().unwrap().insert(id.clone());
+    }
+    let stored = match &state.ingest {
+        Some(queue) => queue.submit(prepared).await,
+        None => messages::store_prepared(state, prepared, false).await,
+    };
+    // nothing under `id` is coming back when it wasn't stored, or was stored under a new id
+    let echoed = stored.as_ref().is_ok_and(|(msg, _)| msg.id == id);
+    if let Some(own_ids) = own_ids.filter(|_| !echoed) {
+        own_ids.lock().unwrap().remove(&id);
+    }
+    stored
+}
+
+// Whether reading failed on a frame or message over MAX_WS_MESSAGE_BYTES
+fn too_large(err: &axum::Error) -> bool {
+    err.source()
+        .and_then(|err| err.downcast_ref::<tungstenite::Error>())
+        .is_some_and(|err| matches!(err, tungstenite::Error::Capacity(_)))
+}
+
+async fn handle_upgrade(
+    socket: WebSocket,
+    addr: IpAddr,
+    user_agent: String,
+    state: Arc<AppState>,
+    _slot: ConnectionSlot,
+    query: WsQuery,
+) {
+    let connection_id = if state.config.log_connections {
+        let user_id = query.user_id.clone();
+        Some(connections::record_connect(&state, user_id, addr, user_agent.clone()).await)
+    } else {
+        None
+    };
+    // notified when an admin kicks the socket
+    let kick = Arc::new(Notify::new());
+    let codec = Codec::negotiated(&socket);
+    // split the websocket stream into a sender (sink) and receiver (stream)
+    let (mut sink, mut stream) = socket.split();
+    // create an mpsc so we can send frames to the sink from multiple threads
+    let (sender, mut receiver) = mpsc::channel::<ServerFrame>(16);
+
+    // spawn a task that encodes frames from the mpsc and forwards them to the sink,
+    // until it's kicked and closes the socket instead
+    let encode_task_kick = kick.clone();
+    let mut encode_task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                frame = receiver.recv() => {
+                    let Some(frame) = frame else {
+                        break;
+                    };
+                    if sink.send(codec.encode(&frame)).await.is_err() {
+                        break;
+                    }
+                }
+                _ = encode_task_kick.notified() => {
+                    let close = CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "kicked by an admin".into(),
+                    };
+                    let _ = sink.send(Message::Close(Some(close))).await;
+                    break;
+                }
+            }
+        }
+    });
+
+    // unlike ping/pong this is visible to the app, the first beat comes one interval after connecting
+    let heartbeat_task = state.config.heartbeat_interval.map(|period| {
+        let heartbeat_sender = sender.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                let heartbeat = ServerFrame::Heartbeat {
+                    server_time: msg::now_millis(),
+                };
+                if heartbeat_sender.send(heartbeat).await.is_err() {
+                    break;
+                }
+            }
+        })
+    });
+
+    // where the socket picks up from, a bad token falls back to a fresh start after reporting it
+    let pending_acks = query.resend_unacked.then(|| {
+        let (pending, deadlines) = PendingAcks::new(state.config.ack_timeout);
+        let resend_task = tokio::spawn(resend_unacked(pending.clone(), deadlines, sender.clone()));
+        (pending, resend_task)
+    });
+    let (pending_acks, resend_task) = pending_acks.unzip();
+
+    let (mut sent, resume_error) = match query.resume {
+        Some(token) => match resume::take(&state, token).await {
+            Ok(sent) => (sent, None),
+            Err(err) => (resume::heads(&state).await, Some(err)),
+        },
+        None => (resume::heads(&state).await, None),
+    };
+
+    // subscribe to the chat channel
+    let mut rx_chat = state.subscribe();
+
+    let resume_token = resume::new_token();
+    let mut replay = vec![ServerFrame::Resume {
+        token: resume_token.clone(),
+    }];
+    if let Some(err) = resume_error {
+        replay.push(ServerFrame::Error {
+            message: err.message,
+            code: None,
+        });
+    }
+    // resend what the user missed since its last acks, then anything past where the socket picks up from,
+    // subscribed first so nothing falls between the replay and the live feed
+    if let Some(user_id) = &query.user_id {
+        match deliveries::undelivered(&state, user_id.clone()).await {
+            Ok(messages) => replay.extend(messages.into_iter().map(|msg| {
+                sent.advance(&msg);
+                ServerFrame::Message(Box::new(msg))
+            })),
+            Err(err) => replay.push(ServerFrame::Error {
+                message: err.message,
+                code: None,
+            }),
+        }
+    }
+    match resume::missed(&state, sent.clone()).await {
+        Ok(messages) => replay.extend(
+            messages
+                .into_iter()
+                .filter(|msg| sent.advance(msg))
+                .map(|msg| ServerFrame::Message(Box::new(msg))),
+        ),
+        Err(err) => replay.push(ServerFrame::Error {
+            message: err.message,
+            code: None,
+        }),
+    }
+    for frame in replay {
+        if let (ServerFrame::Message(msg), Some(pending)) = (&frame, &pending_acks) {
+            pending.track(msg);
+        }
+        if sender.send(frame).await.is_err() {
+            return;
+        }
+    }
+    // the live feed skips what the replay covered, and keeps `sent` up to date for the next resume token
+    let replayed = sent.clone();
+    let sent = Arc::new(Mutex::new(sent));
+
+    // the user id this socket connected or last sent a message as, used to record its delivery acks
+    let (user_id_tx, user_id_rx) = watch::channel::<Option<String>>(query.user_id);
+    // keeps the last user the socket sent as for the connection log, after the tasks are gone
+    let last_user_id = user_id_rx.clone();
+    let live_id = connection_id.clone().unwrap_or_else(uuidv7::create);
+    state
+        .live_connections
+        .add(live_id.clone(), addr, user_agent, user_id_rx, kick);
+    // ids of the messages this socket sent whose echo hasn't come back yet, when echoes are suppressed
+    let own_ids = state
+        .config
+        .suppress_echo
+        .then(|| Arc::new(Mutex::new(HashSet::new())));
+
+    // whenever a frame is sent to rx_chat, forward it to the mpsc
+    let send_task_sender = sender.clone();
+    let send_task_sent = sent.clone();
+    let send_task_pending = pending_acks.clone();
+    let send_task_own_ids = own_ids.clone();
+    let mut send_task = tokio::spawn(async move {
+        while let Ok(frame) = rx_chat.recv().await {
+            if let ServerFrame::Message(msg) = &frame {
+                if replayed.covers(msg) {
+                    continue;
+                }
+                // suppressed echoes count as sent, the sender already has them
+                send_task_sent.lock().unwrap().advance(msg);
+                if send_task_own_ids
+                    .as_ref()
+                    .is_some_and(|own_ids| own_ids.lock().unwrap().remove(&msg.id))
+                {
+                    continue;
+                }
+                if let Some(pending) = &send_task_pending {
+                    pending.track(msg);
+                }
+            }
+            if send_task_sender.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // whenever a user sends a frame, handle it and reply over the mpsc
+    let recv_task_sender = sender.clone();
+    let recv_task_state = state.clone();
+    let mut recv_task = tokio::spawn(async move {
+        // ids handed out by `reserve_id`, a chat message may only claim one of these
+        let mut reserved_ids = HashSet::new();
+        while let Some(received) = stream.next().await {
+            let message = match received {
+                Ok(message) => message,
+                // the socket can't be read past a message that's too large, so it's closed after saying why
+                Err(err) if too_large(&err) => {
+                    let too_large = ServerFrame::Error {
+                        message: format!(
+                            "messages over {} bytes aren't accepted",
+                            recv_task_state.config.max_ws_message_len
+                        ),
+                        code: Some("frame_too_large"),
+                    };
+                    if recv_task_sender.send(too_large).await.is_ok() {
+                        tokio::time::sleep(TOO_LARGE_GRACE).await;
+                    }
+                    break;
+                }
+                Err(_) => break,
+            };
+            match message {
+                Message::Close(_) => break,
+                // pings are answered by axum, pongs need no reply
+                Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Text(_) | Message::Binary(_) => {}
+            }
+            let reply = match codec.decode(&message) {
+                Ok(ClientFrame::Chat(payload))
+                    if payload
+                        .id
+                        .as_ref()
+                        .is_some_and(|id| !reserved_ids.contains(id)) =>
+                {
+                    Some(ServerFrame::Error {
+                        message: String::from("id was not reserved on this connection"),
+                        code: None,
+                    })
+                }
+                Ok(ClientFrame::Chat(payload)) => {
+                    user_id_tx.send_replace(Some(payload.user_id.clone()));
+                    let inserted =
+                        store_chat(&recv_task_state, *payload, own_ids.as_deref()).await;
+                    match inserted {
+                        // the sender's own socket is among the receivers, echoed or not
+                        Ok((msg, receivers)) => {
+                            // a rejected message keeps its reservation, so it can be fixed and resent
+                            reserved_ids.remove(&msg.id);
+                            Some(ServerFrame::Ack {
+                                id: msg.id,
+                                delivered_to: receivers.saturating_sub(1),
+                            })
+                        }
+                        Err(err) => Some(ServerFrame::Error {
+                            message: err.message,
+                            code: None,
+                        }),
+                    }
+                }
+                Ok(ClientFrame::ReserveId) if reserved_ids.len() >= MAX_RESERVED_IDS => {
+                    Some(ServerFrame::Error {
+                        message: format!(
+                            "at most {MAX_RESERVED_IDS} ids can be reserved at once, send messages under them first"
+                        ),
+                        code: None,
+                    })
+                }
+                Ok(ClientFrame::ReserveId) => {
+                    let id = uuidv7::create();
+                    reserved_ids.insert(id.clone());
+                    Some(ServerFrame::Id { id })
+                }
+                Ok(ClientFrame::AckDelivery { id }) => {
+                    let awaited = pending_acks.as_ref().is_some_and(|pending| pending.ack(&id));
+                    let user_id = user_id_tx.borrow().clone();
+                    match user_id {
+                        Some(user_id) => deliveries::record_ack(&recv_task_state, user_id, id)
+                            .await
+                            .err()
+                            .map(|err| ServerFrame::Error {
+                                message: err.message,
+                                code: None,
+                            }),
+                        // stopping the resend was all it was for, there's no user to record it for
+                        None if awaited => None,
+                        None => Some(ServerFrame::Error {
+                            message: String::from(
+                                "connect with ?user_id= or send a chat message before acking",
+                            ),
+                            code: None,
+                        }),
+                    }
+                }
+                Err(message) => Some(ServerFrame::Error {
+                    message,
+                    code: None,
+                }),
+            };
+            // delivery acks are only answered when they fail
+            let Some(reply) = reply else {
+                continue;
+            };
+            if recv_task_sender.send(reply).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = (&mut send_task) => recv_task.abort(),
+        _ = (&mut recv_task) => send_task.abort(),
+        _ = (&mut encode_task) => {
+            recv_task.abort();
+            send_task.abort();
+        }
+    };
+    state.live_connections.remove(&live_id);
+    // they hold senders, so the encoder task only ends once they're gone
+    if let Some(heartbeat_task) = heartbeat_task {
+        heartbeat_task.abort();
+    }
+    if let Some(resend_task) = resend_task {
+        resend_task.abort();
+    }
+
+    let sent = sent.lock().unwrap().clone();
+    resume::save(&state, resume_token, sent).await;
+
+    if let Some(connection_id) = connection_id {
+        let user_id = last_user_id.borrow().clone();
+        connections::record_disconnect(&state, connection_id, user_id).await;
+    }
+}